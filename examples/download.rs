@@ -13,17 +13,19 @@ use std::env::temp_dir;
 use downloader::Downloader;
 
 // Define a custom progress reporter:
+#[cfg(not(feature = "tui"))]
 struct SimpleReporterPrivate {
     last_update: std::time::Instant,
     max_progress: Option<u64>,
     message: String,
 }
+#[cfg(not(feature = "tui"))]
 struct SimpleReporter {
     private: std::sync::Mutex<Option<SimpleReporterPrivate>>,
 }
 
+#[cfg(not(feature = "tui"))]
 impl SimpleReporter {
-    #[cfg(not(feature = "tui"))]
     fn create() -> std::sync::Arc<Self> {
         std::sync::Arc::new(Self {
             private: std::sync::Mutex::new(None),
@@ -31,6 +33,7 @@ impl SimpleReporter {
     }
 }
 
+#[cfg(not(feature = "tui"))]
 impl downloader::progress::Reporter for SimpleReporter {
     fn setup(&self, max_progress: Option<u64>, message: &str) {
         let private = SimpleReporterPrivate {
@@ -100,6 +103,6 @@ fn main() {
         match r {
             Err(e) => println!("Error: {e}"),
             Ok(s) => println!("Success: {}", &s),
-        };
+        }
     }
 }
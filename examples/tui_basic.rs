@@ -35,6 +35,6 @@ fn main() {
         match r {
             Err(e) => print!("Error occurred! {e}"),
             Ok(s) => print!("Success: {}", &s),
-        };
+        }
     }
 }
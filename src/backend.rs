@@ -3,173 +3,1054 @@
 
 //! The actual download code
 
-use crate::{Download, DownloadSummary, Error, Result, Verification};
+use crate::download::{ExistingFilePolicy, Target, Transfer};
+use crate::mirror::MirrorHealth;
+use crate::validators::Validators;
+use crate::{
+    Attempt, AttemptOutcome, Completion, Download, DownloadSummary, DropReason, Error, Result,
+    Verification,
+};
 
 use futures::stream::{self, StreamExt};
-use rand::seq::SliceRandom;
 
-use std::io::{Seek, SeekFrom, Write};
+use std::convert::TryFrom;
 
-fn select_url(urls: &[String]) -> String {
+mod segmented;
+mod sink;
+mod swarm;
+
+use sink::Sink;
+
+/// Whether `error` comes from the TLS implementation
+#[cfg_attr(
+    not(any(feature = "default-tls", feature = "rustls-tls")),
+    allow(unused_variables)
+)]
+fn is_tls_implementation_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    #[cfg(feature = "default-tls")]
+    if error.is::<native_tls::Error>() {
+        return true;
+    }
+    #[cfg(feature = "rustls-tls")]
+    if error.is::<rustls::Error>() {
+        return true;
+    }
+    false
+}
+
+/// Whether `error` happened while setting up TLS
+///
+/// The messages of the errors are of no use here: The one of `error` itself
+/// contains the URL, which might well mention "ssl" or "tls".
+fn is_tls_error(error: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(e) = source {
+        if is_tls_implementation_error(e) {
+            return true;
+        }
+        // An `io::Error` does not report the error it wraps as its source:
+        source = e
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::get_ref)
+            .map(|inner| inner as &(dyn std::error::Error + 'static))
+            .or_else(|| e.source());
+    }
+    false
+}
+
+/// Classify a `reqwest::Error`
+fn outcome_of(error: &reqwest::Error) -> AttemptOutcome {
+    let message = error.to_string();
+    if error.is_timeout() {
+        AttemptOutcome::Timeout
+    } else if error.is_connect() {
+        if is_tls_error(error) {
+            AttemptOutcome::Tls(message)
+        } else {
+            AttemptOutcome::Connect(message)
+        }
+    } else if error.is_body() || error.is_decode() {
+        AttemptOutcome::Body(message)
+    } else if error.is_builder() || error.is_request() && error.url().is_none() {
+        AttemptOutcome::Request(message)
+    } else {
+        AttemptOutcome::Connect(message)
+    }
+}
+
+impl Attempt {
+    fn failed(url: &str, error: &reqwest::Error, started: std::time::Instant) -> Self {
+        Self {
+            url: url.to_owned(),
+            final_url: None,
+            outcome: outcome_of(error),
+            bytes: 0,
+            duration: started.elapsed(),
+            resumed: false,
+            retry_after: None,
+        }
+    }
+
+    fn answered(url: &str, response: &reqwest::Response, started: std::time::Instant) -> Self {
+        let status = response.status();
+        let retry_after = if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+        {
+            response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(crate::retry::parse_retry_after)
+        } else {
+            None
+        };
+        Self {
+            url: url.to_owned(),
+            final_url: Some(response.url().to_string()),
+            outcome: AttemptOutcome::Status(status.as_u16()),
+            bytes: 0,
+            duration: started.elapsed(),
+            resumed: false,
+            retry_after,
+        }
+    }
+
+    fn with_outcome(mut self, outcome: AttemptOutcome) -> Self {
+        self.outcome = outcome;
+        self
+    }
+
+    /// Record that `bytes` were received in the time since `started`
+    fn finished(mut self, bytes: u64, started: std::time::Instant) -> Self {
+        self.bytes = bytes;
+        self.duration = started.elapsed();
+        self
+    }
+
+    /// The reason to drop the mirror after this attempt, if any
+    ///
+    /// Mirrors are dropped after permanent errors, server errors and
+    /// connection problems. A server asking to come back later is not
+    /// dropped.
+    fn drop_reason(&self) -> Option<DropReason> {
+        match &self.outcome {
+            AttemptOutcome::Status(503) if self.retry_after.is_some() => None,
+            AttemptOutcome::Status(status)
+                if *status >= 500 || !self.outcome.is_retryable() && !self.outcome.is_success() =>
+            {
+                Some(DropReason::Status(*status))
+            }
+            AttemptOutcome::Status(_)
+            | AttemptOutcome::Body(_)
+            | AttemptOutcome::Truncated { .. }
+            | AttemptOutcome::Write(_)
+            | AttemptOutcome::TooLarge { .. } => None,
+            AttemptOutcome::UnexpectedSize { .. } | AttemptOutcome::UnexpectedContentType(_) => {
+                Some(DropReason::UnexpectedContent(self.outcome.to_string()))
+            }
+            AttemptOutcome::Connect(message) | AttemptOutcome::Request(message) => {
+                Some(DropReason::Connection(message.clone()))
+            }
+            AttemptOutcome::Timeout => Some(DropReason::Timeout),
+            AttemptOutcome::Tls(message) => Some(DropReason::Tls(message.clone())),
+        }
+    }
+}
+
+/// Drop the mirror `url` if `attempt` calls for it
+///
+/// Transient failures keep the last healthy mirror around so that it can
+/// be retried. Returns `true` if the mirror got dropped.
+fn drop_failed_mirror(mirrors: &mut MirrorHealth, url: &str, attempt: &Attempt) -> bool {
+    let Some(reason) = attempt.drop_reason() else {
+        return false;
+    };
+    if attempt.outcome.is_retryable() && mirrors.healthy().len() <= 1 {
+        return false;
+    }
+    mirrors.drop_mirror(url, reason);
+    true
+}
+
+/// Drop the mirror `url` after its server asked to wait for longer than the
+/// retry policy allows
+///
+/// Other mirrors are tried instead, if there are any.
+fn drop_busy_mirror(mirrors: &mut MirrorHealth, url: &str, attempt: &Attempt) {
+    if let Some(status) = attempt.status() {
+        mirrors.drop_mirror(url, DropReason::Status(status));
+    }
+}
+
+/// Settings shared by all downloads of one `Downloader`
+#[derive(Clone)]
+pub(crate) struct Context {
+    pub client: reqwest::Client,
+    pub timeout: Option<std::time::Duration>,
+    pub retries: u16,
+    pub retry_policy: crate::RetryPolicy,
+    pub host_stats: crate::mirror::HostStats,
+    pub host_limits: crate::throttle::HostLimits,
+    pub bandwidth: Option<crate::throttle::Bandwidth>,
+    pub download_bandwidth: Option<crate::throttle::Bandwidth>,
+    pub conditions: Option<Validators>,
+    pub cache: Option<crate::cache::Cache>,
+}
+
+impl Context {
+    /// Wait before the next attempt after `failures` failed ones
+    ///
+    /// Returns `false` without waiting if the server asked to wait for longer
+    /// than the retry policy allows.
+    async fn wait(&self, failures: u16, attempt: &Attempt) -> bool {
+        let Some(delay) = self.retry_policy.delay(failures, attempt.retry_after) else {
+            return false;
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        true
+    }
+
+    /// Make `request` to `url` conditional on the data having changed since
+    /// the existing file was downloaded
+    fn if_changed(&self, url: &str, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self
+            .conditions
+            .as_ref()
+            .filter(|c| c.url.as_deref().is_none_or(|u| u == url))
+        {
+            Some(conditions) => conditions.if_changed(request),
+            None => request,
+        }
+    }
+
+    /// Whether `attempt` reported the existing file to be up to date
+    fn is_unchanged(&self, attempt: &Attempt) -> bool {
+        self.conditions.is_some()
+            && attempt.status() == Some(reqwest::StatusCode::NOT_MODIFIED.as_u16())
+    }
+
+    /// Limit the time the whole `request` may take, unless its data gets
+    /// throttled
+    fn limit_time(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.timeout {
+            Some(timeout) if self.bandwidth.is_none() && self.download_bandwidth.is_none() => {
+                request.timeout(timeout)
+            }
+            _ => request,
+        }
+    }
+
+    /// Wait till `bytes` more bytes may be received
+    async fn throttle(&self, bytes: u64) {
+        for bandwidth in self.bandwidth.iter().chain(&self.download_bandwidth) {
+            bandwidth.consume(bytes).await;
+        }
+    }
+}
+
+fn select_url(context: &Context, download: &Download, urls: &[String], attempt: u16) -> String {
     assert!(!urls.is_empty());
-    urls.choose(&mut rand::thread_rng()).unwrap().clone()
+    let selector = download
+        .mirror_selector
+        .as_ref()
+        .expect("This has been set!");
+    let index = selector.select(urls, attempt, &context.host_stats);
+    urls[index.min(urls.len() - 1)].clone()
+}
+
+/// Measure the latency of all hosts in `urls` that were not measured before
+async fn probe_latencies(context: &Context, urls: &[String]) {
+    let probes = urls
+        .iter()
+        .filter(|u| context.host_stats.get(u).and_then(|r| r.latency).is_none())
+        .map(|u| async move {
+            let _permit = context.host_limits.acquire(u).await;
+            let start = std::time::Instant::now();
+            if context
+                .limit_time(context.client.head(u))
+                .send()
+                .await
+                .is_ok()
+            {
+                context.host_stats.record_latency(u, start.elapsed());
+            } else {
+                context.host_stats.record_failure(u);
+            }
+        });
+    futures::future::join_all(probes).await;
+}
+
+/// The temporary file a download is written to before it is moved into place.
+fn part_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    std::path::PathBuf::from(part)
+}
+
+/// The temporary file data taken from the cache is verified in.
+fn cached_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut cached = path.as_os_str().to_owned();
+    cached.push(".cached");
+    std::path::PathBuf::from(cached)
+}
+
+fn remove_part_file(part_file: &std::path::Path) {
+    _ = std::fs::remove_file(part_file);
+    Validators::remove(part_file);
+}
+
+/// The start offset and the complete length reported in a `Content-Range` header
+fn content_range(headers: &reqwest::header::HeaderMap) -> Option<(u64, Option<u64>)> {
+    let value = headers.get(reqwest::header::CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    let (range, total) = range.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()))
+}
+
+/// Check the size and content type `response` announces against what
+/// `download` expects
+///
+/// `offset` is where the data in `response` starts.
+fn unexpected_content(
+    download: &Download,
+    response: &reqwest::Response,
+    offset: u64,
+) -> Option<AttemptOutcome> {
+    if let Some(expected) = download.expected_size {
+        let actual = match content_range(response.headers()) {
+            Some((_, Some(total))) => Some(total),
+            _ => response.content_length().map(|l| l + offset),
+        };
+        if let Some(actual) = actual.filter(|a| *a != expected) {
+            return Some(AttemptOutcome::UnexpectedSize { expected, actual });
+        }
+    }
+
+    if download.content_types.is_empty() {
+        return None;
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_lowercase());
+    let accepted = content_type.as_ref().is_some_and(|actual| {
+        download.content_types.iter().any(|allowed| {
+            allowed == actual
+                || allowed.strip_suffix("/*").is_some_and(|kind| {
+                    actual
+                        .split_once('/')
+                        .is_some_and(|(actual_kind, _)| actual_kind == kind)
+                })
+        })
+    });
+    (!accepted).then_some(AttemptOutcome::UnexpectedContentType(content_type))
+}
+
+/// Pass the body of `response` on to `sink`
+///
+/// The data in `response` starts at `start`, of which `skip` bytes were
+/// passed on already. Failures are recorded in `attempt`. Returns the
+/// number of bytes received.
+async fn receive(
+    context: &Context,
+    response: &mut reqwest::Response,
+    sink: &mut Sink,
+    download: &Download,
+    attempt: &mut Attempt,
+    start: u64,
+    mut skip: u64,
+) -> u64 {
+    let progress = download.progress.as_ref().expect("This has been set!");
+    let mut current = start;
+    let mut received = 0;
+    loop {
+        let bytes = match response.chunk().await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => break,
+            Err(e) => {
+                attempt.outcome = AttemptOutcome::Body(e.to_string());
+                break;
+            }
+        };
+        let length = bytes.len() as u64;
+        context.throttle(length).await;
+        received += length;
+        current += length;
+        progress.progress(current);
+        if let Some(limit) = sink.limit().filter(|l| current > *l) {
+            attempt.outcome = AttemptOutcome::TooLarge { limit };
+            break;
+        }
+        if let Some(expected) = download.expected_size.filter(|e| current > *e) {
+            attempt.outcome = AttemptOutcome::UnexpectedSize {
+                expected,
+                actual: current,
+            };
+            break;
+        }
+
+        // Data passed on in an earlier attempt already:
+        let known = skip.min(length);
+        skip -= known;
+        if known == length {
+            continue;
+        }
+        if let Err(e) = sink
+            .write(bytes.slice(usize::try_from(known).unwrap_or(usize::MAX)..))
+            .await
+        {
+            attempt.outcome = AttemptOutcome::Write(e.to_string());
+            break;
+        }
+    }
+    received
 }
 
 async fn download_url(
-    client: reqwest::Client,
-    url: String,
-    writer: &mut std::io::BufWriter<std::fs::File>,
-    progress: &mut crate::Progress,
+    context: &Context,
+    url: &str,
+    sink: &mut Sink,
+    download: &Download,
     message: &str,
-) -> u16 {
-    if let Ok(mut response) = client.get(&url).send().await {
-        let total = response.content_length();
-        let mut current: u64 = 0;
-        writer.seek(SeekFrom::Start(current)).unwrap_or(0);
+    offset: u64,
+    validators: &mut Validators,
+) -> Attempt {
+    let progress = download.progress.as_ref().expect("This has been set!");
+    let _permit = context.host_limits.acquire(url).await;
+    let start = std::time::Instant::now();
+    let mut request = context.limit_time(context.client.get(url));
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+        if let Some(if_range) = validators.if_range() {
+            request = request.header(reqwest::header::IF_RANGE, if_range);
+        }
+    } else {
+        request = context.if_changed(url, request);
+    }
 
-        progress.setup(total, message);
+    let mut response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            context.host_stats.record_failure(url);
+            return Attempt::failed(url, &e, start);
+        }
+    };
+    context.host_stats.record_latency(url, start.elapsed());
 
-        while let Some(bytes) = response.chunk().await.unwrap_or(None) {
-            if writer.write_all(&bytes).is_err() {}
+    let mut attempt = Attempt::answered(url, &response, start);
+    if context.is_unchanged(&attempt) {
+        return attempt;
+    }
+    let status = response.status();
+    if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // Our partial data does not fit the resource: Start over next time.
+        *validators = Validators::default();
+    }
+    if !status.is_success() {
+        context.host_stats.record_failure(url);
+        return attempt;
+    }
 
-            current += bytes.len() as u64;
-            progress.progress(current);
+    let current = if status == reqwest::StatusCode::PARTIAL_CONTENT {
+        if content_range(response.headers()).map(|(start, _)| start) != Some(offset) {
+            *validators = Validators::default();
+            return attempt.with_outcome(AttemptOutcome::Status(
+                reqwest::StatusCode::RANGE_NOT_SATISFIABLE.as_u16(),
+            ));
         }
+        attempt.resumed = true;
+        offset
+    } else {
+        // The server ignored our range request (or it was never sent):
+        0
+    };
+    if let Some(outcome) = unexpected_content(download, &response, current) {
+        context.host_stats.record_failure(url);
+        return attempt.with_outcome(outcome);
+    }
+    *validators = Validators::from_response(&response);
+
+    let skip = match sink.rewind(current) {
+        Ok(skip) => skip,
+        Err(e) => return attempt.with_outcome(AttemptOutcome::Write(e.to_string())),
+    };
+
+    let expected = response.content_length();
+    if let Some(limit) = sink.limit() {
+        if expected.is_some_and(|l| l + current > limit) {
+            return attempt.with_outcome(AttemptOutcome::TooLarge { limit });
+        }
+    }
+    progress.setup(expected.map(|l| l + current), message);
+    progress.progress(current);
 
-        let result = response.status().as_u16();
-        progress.set_message(&format!("{message} - {result}"));
-        result
+    let received = receive(
+        context,
+        &mut response,
+        sink,
+        download,
+        &mut attempt,
+        current,
+        skip,
+    )
+    .await;
+    let current = current + received;
+
+    if let Some(expected) = expected {
+        if attempt.outcome.is_success() && received != expected {
+            attempt = attempt.with_outcome(AttemptOutcome::Truncated { expected, received });
+        }
+    }
+    if let Some(expected) = download.expected_size {
+        if attempt.outcome.is_success() && current != expected {
+            attempt = attempt.with_outcome(AttemptOutcome::UnexpectedSize {
+                expected,
+                actual: current,
+            });
+        }
+    }
+
+    let attempt = attempt.finished(received, start);
+    if attempt.outcome.is_success() {
+        context
+            .host_stats
+            .record_success(url, received, attempt.duration);
     } else {
-        reqwest::StatusCode::BAD_REQUEST.as_u16()
+        context.host_stats.record_failure(url);
     }
+
+    progress.set_message(&format!("{message} - {}", attempt.outcome));
+    attempt
 }
 
+/// Turn a failure to write the data into an `std::io::Error`
+fn write_error(attempt: &Attempt) -> std::io::Result<()> {
+    match &attempt.outcome {
+        AttemptOutcome::Write(message) => Err(std::io::Error::other(message.clone())),
+        _ => Ok(()),
+    }
+}
+
+/// Verify the file at `path`
+///
+/// `streamed` is the result of the streaming verification, if there was
+/// any. `streaming` is set if the streaming verification still needs to
+/// read the file as it was not fed the data during the download.
 async fn verify_download(
     path: std::path::PathBuf,
-    verify_callback: crate::Verify,
+    verify_callback: Option<crate::Verify>,
+    async_verify_callback: Option<crate::AsyncVerify>,
+    streaming: Option<crate::verify::Streaming>,
+    streamed: Verification,
     progress: crate::Progress,
     message: &str,
 ) -> Verification {
     let p = progress.clone();
-    let result =
-        tokio::task::spawn_blocking(move || verify_callback(path, &move |c: u64| p.progress(c)))
-            .await
-            .unwrap_or(crate::Verification::NotVerified);
-    progress.set_message(&format!(
-        "{} - {}",
-        message,
-        match result {
-            Verification::NotVerified => "not verified",
-            Verification::Failed => "FAILED",
-            Verification::Ok => "Ok",
+    let blocking_path = path.clone();
+    let mut result = tokio::task::spawn_blocking(move || {
+        let path = blocking_path;
+        let cb = move |c: u64| p.progress(c);
+        let streamed = streaming.map_or(streamed, |streaming| {
+            crate::verify::verify_file(&streaming, &path, &cb)
+        });
+        match verify_callback {
+            Some(verify_callback) => streamed.and(verify_callback(path, &cb)),
+            None => streamed,
         }
-    ));
+    })
+    .await
+    .unwrap_or(crate::Verification::NotVerified);
+    if let Some(callback) = async_verify_callback.filter(|_| !result.is_failed()) {
+        result = result.and(callback(path).await);
+    }
+    progress.set_message(&format!("{message} - {result}"));
     progress.done();
     result
 }
 
-async fn download(
-    client: reqwest::Client,
-    mut download: Download,
-    retries: u16,
-) -> Result<DownloadSummary> {
-    let mut summary = DownloadSummary {
-        status: Vec::new(),
-        file_name: std::mem::take(&mut download.file_name),
-        verified: Verification::NotVerified,
+fn display_name(file_name: &std::path::Path) -> std::borrow::Cow<'_, str> {
+    file_name
+        .file_name()
+        .unwrap_or_else(|| std::ffi::OsStr::new("<unknown>"))
+        .to_string_lossy()
+}
+
+fn status_message(file_name: &std::path::Path, retry: u16, retries: u16) -> String {
+    format!("{} {}/{}", display_name(file_name), retry, retries)
+}
+
+/// Fetch `download` into its target, retrying as needed.
+///
+/// `Target::File` downloads go into `part_file`, the data of
+/// `Target::Memory` downloads ends up in the `summary`.
+///
+/// Returns `true` if the data was downloaded successfully and an error
+/// if writing the data failed.
+async fn fetch(
+    context: &Context,
+    download: &Download,
+    part_file: &std::path::Path,
+    mirrors: &mut MirrorHealth,
+    summary: &mut DownloadSummary,
+    message: &mut String,
+) -> std::io::Result<bool> {
+    let resume = download.resume && download.target.is_file();
+    let mut sink = Sink::open(
+        &download.target,
+        part_file,
+        resume,
+        download.streaming_verify.clone(),
+    )?;
+
+    let mut validators = if resume {
+        Validators::load(part_file)
+    } else {
+        Validators::default()
     };
 
-    let mut urls = std::mem::take(&mut download.urls);
-    assert!(!urls.is_empty());
+    // Data of unknown origin is only continued until the server objects:
+    let mut unvalidated = resume && download.existing_file == Some(ExistingFilePolicy::Resume);
 
-    let mut progress = download.progress.expect("This has been set!").clone();
-    let mut message = String::new();
+    let retries = context.retries;
+    for retry in 1..=retries {
+        if mirrors.healthy().is_empty() {
+            break;
+        }
 
-    let mut download_successful = false;
+        // Continue where the last attempt (or the last run) stopped:
+        let offset = if validators.if_range().is_some() || unvalidated {
+            sink.resume_offset()
+        } else {
+            0
+        };
+        let url = select_url(context, download, mirrors.healthy(), retry);
 
-    if let Ok(file) = std::fs::OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(&summary.file_name)
-    {
-        let mut writer = std::io::BufWriter::new(file);
-
-        for retry in 1..=retries {
-            let url = select_url(&urls);
-
-            message = format!(
-                "{} {}/{}",
-                &summary
-                    .file_name
-                    .file_name()
-                    .unwrap_or_else(|| std::ffi::OsStr::new("<unknown>"))
-                    .to_string_lossy(),
-                retry,
-                retries,
-            );
+        *message = status_message(&summary.file_name, retry, retries);
 
-            let s = reqwest::StatusCode::from_u16(
-                download_url(
-                    client.clone(),
-                    url.clone(),
-                    &mut writer,
-                    &mut progress,
-                    &message,
-                )
-                .await,
-            )
-            .unwrap_or(reqwest::StatusCode::BAD_REQUEST);
-
-            summary.status.push((url.clone(), s.as_u16()));
-
-            if s.is_server_error() {
-                urls = urls
-                    .iter()
-                    .filter_map(|u| if u == &url { Some(u.clone()) } else { None })
-                    .collect();
-                if urls.is_empty() {
-                    break;
-                }
+        let attempt = download_url(
+            context,
+            &url,
+            &mut sink,
+            download,
+            message,
+            offset,
+            &mut validators,
+        )
+        .await;
+
+        if resume {
+            if validators.if_range().is_some() {
+                _ = validators.store(part_file);
+            } else {
+                Validators::remove(part_file);
             }
+        }
+
+        if attempt.status() == Some(reqwest::StatusCode::RANGE_NOT_SATISFIABLE.as_u16()) {
+            unvalidated = false;
+        }
+        summary.status.push(attempt.clone());
+        write_error(&attempt)?;
 
-            if s.is_success() {
-                download_successful = true;
+        if context.is_unchanged(&attempt) {
+            summary.completion = Completion::UpToDate;
+            return Ok(true);
+        }
+        if attempt.outcome.is_success() {
+            remember_validators(download, part_file, &url, &validators);
+            sink.flush().await?;
+            (summary.verified, summary.data) = sink.finish();
+            return Ok(true);
+        }
+
+        if !drop_failed_mirror(mirrors, &url, &attempt) {
+            if !attempt.outcome.is_retryable() {
                 break;
             }
+            if retry < retries && !context.wait(retry, &attempt).await {
+                drop_busy_mirror(mirrors, &url, &attempt);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Fetch `download` with its `Transfer` mode, falling back to a single
+/// request if necessary
+async fn transfer(
+    context: &Context,
+    download: &Download,
+    part_file: &std::path::Path,
+    mirrors: &mut MirrorHealth,
+    summary: &mut DownloadSummary,
+    message: &mut String,
+) -> std::io::Result<bool> {
+    let ranged = match download.transfer {
+        Transfer::Single => None,
+        Transfer::Segmented(count) => {
+            segmented::fetch(
+                context, download, part_file, count, mirrors, summary, message,
+            )
+            .await
+        }
+        Transfer::Swarm(chunk_size) => {
+            swarm::fetch(
+                context, download, part_file, chunk_size, mirrors, summary, message,
+            )
+            .await
+        }
+    };
+    match ranged {
+        Some(fetched) => fetched,
+        None if summary.completion == Completion::UpToDate => Ok(true),
+        None => fetch(context, download, part_file, mirrors, summary, message).await,
+    }
+}
+
+/// The ways a download can fail, see `Error`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Failed {
+    File,
+    Download,
+    UnexpectedContent,
+    Verification,
+    FileExists,
+}
+
+impl Failed {
+    const fn into_error(self, summary: DownloadSummary) -> Error {
+        match self {
+            Self::File => Error::File(summary),
+            Self::Download => Error::Download(summary),
+            Self::UnexpectedContent => Error::UnexpectedContent(summary),
+            Self::Verification => Error::Verification(summary),
+            Self::FileExists => Error::FileExists(summary),
         }
     }
+}
 
-    if !download_successful {
-        return Err(Error::Download(summary));
+/// Fetch `download` and verify the data
+///
+/// The data ends up in `summary.file_name` or `summary.data`.
+async fn fetch_verified(
+    context: &Context,
+    download: &Download,
+    part_file: &std::path::Path,
+    mirrors: &mut MirrorHealth,
+    summary: &mut DownloadSummary,
+    message: &mut String,
+) -> std::result::Result<(), Failed> {
+    let is_file = download.target.is_file();
+    let fetched = transfer(context, download, part_file, mirrors, summary, message).await;
+
+    if !matches!(fetched, Ok(true)) {
+        if is_file && !download.resume {
+            remove_part_file(part_file);
+        }
+        let unexpected = summary.status.last().is_some_and(|a| {
+            matches!(
+                a.outcome,
+                AttemptOutcome::UnexpectedSize { .. } | AttemptOutcome::UnexpectedContentType(_)
+            )
+        });
+        return Err(if fetched.is_err() {
+            Failed::File
+        } else if unexpected {
+            Failed::UnexpectedContent
+        } else {
+            Failed::Download
+        });
+    }
+    let progress = download.progress.clone().expect("This has been set!");
+    if summary.completion == Completion::UpToDate {
+        remove_part_file(part_file);
+        progress.done();
+        return Ok(());
+    }
+    if !is_file {
+        progress.done();
+        if summary.verified.is_failed() {
+            summary.data = None;
+            return Err(Failed::Verification);
+        }
+        return Ok(());
     }
+    let mut validators = Validators::load(part_file);
+    Validators::remove(part_file);
 
+    // Ranges and resumed files were not verified while receiving them:
+    let streaming = download
+        .streaming_verify
+        .clone()
+        .filter(|_| summary.verified == Verification::NotVerified);
     summary.verified = verify_download(
-        summary.file_name.clone(),
-        std::mem::replace(&mut download.verify_callback, crate::verify::noop()),
-        progress.clone(),
-        &message,
+        part_file.to_path_buf(),
+        download.verify_callback.clone(),
+        download.async_verify_callback.clone(),
+        streaming,
+        std::mem::replace(&mut summary.verified, Verification::NotVerified),
+        progress,
+        message,
+    )
+    .await;
+    if summary.verified.is_failed() {
+        remove_part_file(part_file);
+        return Err(Failed::Verification);
+    }
+
+    if std::fs::rename(part_file, &summary.file_name).is_err() {
+        remove_part_file(part_file);
+        return Err(Failed::File);
+    }
+
+    if download.existing_file == Some(ExistingFilePolicy::Update) {
+        validators.content_length = std::fs::metadata(&summary.file_name).ok().map(|m| m.len());
+        _ = validators.store_completed(&summary.file_name);
+    }
+    Ok(())
+}
+
+/// Drop the mirrors that served data in `attempts` which failed verification
+///
+/// Returns whether there are mirrors left to download the data from.
+fn drop_unverified_mirrors(
+    mirrors: &mut MirrorHealth,
+    attempts: &[Attempt],
+    verified: &Verification,
+) -> bool {
+    let Verification::Failed(failure) = verified else {
+        return false;
+    };
+    let healthy = mirrors.healthy().len();
+    for attempt in attempts.iter().filter(|a| a.outcome.is_success()) {
+        mirrors.drop_mirror(
+            &attempt.url,
+            DropReason::VerificationFailed(failure.as_ref().clone()),
+        );
+    }
+    // Do not try the same mirrors again:
+    mirrors.healthy().len() < healthy && !mirrors.healthy().is_empty()
+}
+
+/// Keep the `validators` reported by `url` for `ExistingFilePolicy::Update`
+///
+/// They are stored next to the `part_file` until the download is complete.
+/// Downloads that do not go into a file have nothing to update later.
+fn remember_validators(
+    download: &Download,
+    part_file: &std::path::Path,
+    url: &str,
+    validators: &Validators,
+) {
+    if download.target.is_file() && download.existing_file == Some(ExistingFilePolicy::Update) {
+        let mut validators = validators.clone();
+        validators.url = Some(url.to_owned());
+        _ = validators.store(part_file);
+    }
+}
+
+/// The validators stored for `file_name` when it was downloaded
+///
+/// Returns `None` if there are none or the file was changed since.
+fn completed_validators(file_name: &std::path::Path) -> Option<Validators> {
+    let metadata = std::fs::metadata(file_name).ok()?;
+    let validators = Validators::load_completed(file_name);
+    (validators.content_length == Some(metadata.len())
+        && (validators.etag.is_some() || validators.last_modified.is_some()))
+    .then_some(validators)
+}
+
+/// Apply the `ExistingFilePolicy` of `download` if its file exists already
+///
+/// Returns how the download was completed if the existing file is kept.
+async fn keep_existing_file(
+    context: &mut Context,
+    download: &mut Download,
+    part_file: &std::path::Path,
+    summary: &mut DownloadSummary,
+) -> std::result::Result<Option<Completion>, Failed> {
+    if !download.target.is_file() || !summary.file_name.exists() {
+        return Ok(None);
+    }
+    let progress = download.progress.clone().expect("This has been set!");
+
+    match download.existing_file.unwrap_or_default() {
+        ExistingFilePolicy::Fail => Err(Failed::FileExists),
+        ExistingFilePolicy::Overwrite => Ok(None),
+        ExistingFilePolicy::Skip => {
+            progress.done();
+            Ok(Some(Completion::Skipped))
+        }
+        ExistingFilePolicy::Update => {
+            // Ask for the data only if it changed, see `fetch`:
+            context.conditions = completed_validators(&summary.file_name);
+            Ok(None)
+        }
+        ExistingFilePolicy::SkipIfVerified => {
+            let verified = verify_download(
+                summary.file_name.clone(),
+                download.verify_callback.clone(),
+                download.async_verify_callback.clone(),
+                download.streaming_verify.clone(),
+                Verification::NotVerified,
+                progress,
+                &display_name(&summary.file_name),
+            )
+            .await;
+            if verified == Verification::Ok {
+                summary.verified = verified;
+                Ok(Some(Completion::Skipped))
+            } else {
+                Ok(None)
+            }
+        }
+        ExistingFilePolicy::Resume => {
+            // A partial download from an earlier run is more recent:
+            if !part_file.exists() && std::fs::rename(&summary.file_name, part_file).is_err() {
+                return Err(Failed::File);
+            }
+            download.resume = true;
+            Ok(None)
+        }
+    }
+}
+
+/// Take the file of `download` from the `cache` if the cached copy passes
+/// verification
+async fn from_cache(
+    cache: &crate::cache::Cache,
+    key: &str,
+    download: &Download,
+    part_file: &std::path::Path,
+    summary: &mut DownloadSummary,
+) -> bool {
+    // Keep away from `part_file` till the cached data is known to be good:
+    // It might hold data to resume from.
+    let cached_file = cached_path(&summary.file_name);
+    if !cache.fetch(key, &cached_file) {
+        return false;
+    }
+
+    let verified = verify_download(
+        cached_file.clone(),
+        download.verify_callback.clone(),
+        download.async_verify_callback.clone(),
+        download.streaming_verify.clone(),
+        Verification::NotVerified,
+        download.progress.clone().expect("This has been set!"),
+        &display_name(&summary.file_name),
     )
     .await;
-    if summary.verified == Verification::Failed {
-        return Err(Error::Verification(summary));
+    if verified == Verification::Ok && std::fs::rename(&cached_file, &summary.file_name).is_ok() {
+        summary.verified = verified;
+        remove_part_file(part_file);
+        return true;
     }
 
-    Ok(summary)
+    remove_part_file(&cached_file);
+    if verified.is_failed() {
+        cache.remove(key);
+    }
+    false
 }
 
-/// Run the provided list of `downloads`, using the provided `client`
+async fn download(mut context: Context, mut download: Download) -> Result<DownloadSummary> {
+    let mut summary = DownloadSummary {
+        status: Vec::new(),
+        dropped_mirrors: Vec::new(),
+        file_name: download.file_name.clone(),
+        completion: Completion::Downloaded,
+        verified: Verification::NotVerified,
+        data: None,
+    };
+
+    let part_file = part_path(&summary.file_name);
+    match keep_existing_file(&mut context, &mut download, &part_file, &mut summary).await {
+        Ok(Some(completion)) => {
+            summary.completion = completion;
+            return Ok(summary);
+        }
+        Ok(None) => {}
+        Err(failed) => return Err(failed.into_error(summary)),
+    }
+
+    let cache = context.cache.as_ref().filter(|_| download.target.is_file());
+    let cache_key = download
+        .cache_key
+        .clone()
+        .or_else(|| download.urls.first().cloned())
+        .unwrap_or_default();
+    if let Some(cache) = cache {
+        if from_cache(cache, &cache_key, &download, &part_file, &mut summary).await {
+            summary.completion = Completion::Cached;
+            return Ok(summary);
+        }
+    }
+
+    context.download_bandwidth = download.max_bandwidth.map(crate::throttle::Bandwidth::new);
+    let mut message = String::new();
+    let mut mirrors = MirrorHealth::new(&download.urls);
+
+    if download
+        .mirror_selector
+        .as_ref()
+        .is_some_and(|s| s.needs_latency())
+    {
+        probe_latencies(&context, &download.urls).await;
+    }
+
+    let retry =
+        download.retry_verification && matches!(download.target, Target::File | Target::Memory(_));
+    let result = loop {
+        let first_attempt = summary.status.len();
+        let result = fetch_verified(
+            &context,
+            &download,
+            &part_file,
+            &mut mirrors,
+            &mut summary,
+            &mut message,
+        )
+        .await;
+        if result != Err(Failed::Verification)
+            || !retry
+            || !drop_unverified_mirrors(
+                &mut mirrors,
+                &summary.status[first_attempt..],
+                &summary.verified,
+            )
+        {
+            break result;
+        }
+        summary.verified = Verification::NotVerified;
+    };
+    summary.dropped_mirrors = mirrors.into_dropped();
+
+    if let Some(cache) = cache.filter(|_| result.is_ok() && summary.verified == Verification::Ok) {
+        cache.store(&cache_key, &summary.file_name);
+    }
+
+    match result {
+        Ok(()) => Ok(summary),
+        Err(failed) => Err(failed.into_error(summary)),
+    }
+}
+
+/// Run the provided list of `downloads`, using the provided `context`
 pub(crate) fn run(
-    client: &mut reqwest::Client,
+    context: Context,
     downloads: Vec<Download>,
-    retries: u16,
     parallel_requests: u16,
 ) -> Vec<Result<DownloadSummary>> {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let cl = client.clone();
 
     let result = rt.spawn(async move {
         stream::iter(downloads)
-            .map(move |d| download(cl.clone(), d, retries))
+            .map(move |d| download(context.clone(), d))
             .buffer_unordered(parallel_requests as usize)
             .collect::<Vec<Result<DownloadSummary>>>()
             .await
@@ -179,16 +1060,13 @@ pub(crate) fn run(
 }
 
 pub(crate) async fn async_run(
-    client: &mut reqwest::Client,
+    context: Context,
     downloads: Vec<Download>,
-    retries: u16,
     parallel_requests: u16,
 ) -> Vec<Result<DownloadSummary>> {
-    let cl = client.clone();
-
     let result = tokio::spawn(async move {
         stream::iter(downloads)
-            .map(move |d| download(cl.clone(), d, retries))
+            .map(move |d| download(context.clone(), d))
             .buffer_unordered(parallel_requests as usize)
             .collect::<Vec<Result<DownloadSummary>>>()
             .await
@@ -197,3 +1075,190 @@ pub(crate) async fn async_run(
 
     result.unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(content_range: &str) -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::CONTENT_RANGE,
+            reqwest::header::HeaderValue::from_str(content_range).unwrap(),
+        );
+        headers
+    }
+
+    fn attempt(outcome: AttemptOutcome) -> Attempt {
+        Attempt {
+            url: String::from("https://mirror.example/file"),
+            final_url: None,
+            outcome,
+            bytes: 0,
+            duration: std::time::Duration::ZERO,
+            resumed: false,
+            retry_after: None,
+        }
+    }
+
+    /// Whether `outcome` is retried and why its mirror gets dropped
+    fn policy(outcome: AttemptOutcome) -> (bool, Option<DropReason>) {
+        let attempt = attempt(outcome);
+        (attempt.outcome.is_retryable(), attempt.drop_reason())
+    }
+
+    #[test]
+    fn permanent_client_errors() {
+        for status in [400, 401, 403, 404, 410] {
+            assert_eq!(
+                policy(AttemptOutcome::Status(status)),
+                (false, Some(DropReason::Status(status)))
+            );
+        }
+    }
+
+    #[test]
+    fn transient_client_errors() {
+        for status in [408, 416, 425, 429] {
+            assert_eq!(policy(AttemptOutcome::Status(status)), (true, None));
+        }
+    }
+
+    #[test]
+    fn server_errors() {
+        for status in [500, 502, 503, 504] {
+            assert_eq!(
+                policy(AttemptOutcome::Status(status)),
+                (true, Some(DropReason::Status(status)))
+            );
+        }
+
+        let mut busy = attempt(AttemptOutcome::Status(503));
+        busy.retry_after = Some(std::time::Duration::from_secs(5));
+        assert!(busy.outcome.is_retryable());
+        assert_eq!(busy.drop_reason(), None);
+    }
+
+    #[test]
+    fn success() {
+        for status in [200, 206] {
+            assert_eq!(policy(AttemptOutcome::Status(status)), (false, None));
+        }
+    }
+
+    #[test]
+    fn connection_errors() {
+        let message = || String::from("some ssl and tls trouble");
+        assert_eq!(
+            policy(AttemptOutcome::Connect(message())),
+            (true, Some(DropReason::Connection(message())))
+        );
+        assert_eq!(
+            policy(AttemptOutcome::Timeout),
+            (true, Some(DropReason::Timeout))
+        );
+        assert_eq!(
+            policy(AttemptOutcome::Tls(message())),
+            (false, Some(DropReason::Tls(message())))
+        );
+        assert_eq!(
+            policy(AttemptOutcome::Request(message())),
+            (false, Some(DropReason::Connection(message())))
+        );
+    }
+
+    #[test]
+    fn transfer_errors() {
+        assert_eq!(
+            policy(AttemptOutcome::Body(String::from("reset"))),
+            (true, None)
+        );
+        assert_eq!(
+            policy(AttemptOutcome::Truncated {
+                expected: 10,
+                received: 5
+            }),
+            (true, None)
+        );
+        assert_eq!(
+            policy(AttemptOutcome::Write(String::from("disk full"))),
+            (false, None)
+        );
+        assert_eq!(
+            policy(AttemptOutcome::TooLarge { limit: 10 }),
+            (false, None)
+        );
+    }
+
+    #[test]
+    fn unexpected_content() {
+        for outcome in [
+            AttemptOutcome::UnexpectedSize {
+                expected: 10,
+                actual: 20,
+            },
+            AttemptOutcome::UnexpectedContentType(Some(String::from("text/html"))),
+        ] {
+            assert_eq!(
+                policy(outcome.clone()),
+                (
+                    false,
+                    Some(DropReason::UnexpectedContent(outcome.to_string()))
+                )
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_not_tls() {
+        let url = "http://127.0.0.1:1/openssl-3.0-tls-certificate.tar.gz";
+        let error = reqwest::Client::new().get(url).send().await.unwrap_err();
+        assert_eq!(
+            outcome_of(&error),
+            AttemptOutcome::Connect(error.to_string())
+        );
+    }
+
+    #[cfg(any(feature = "default-tls", feature = "rustls-tls"))]
+    #[tokio::test]
+    async fn plain_http_server_is_tls_error() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}/file", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]);
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+        });
+
+        let error = reqwest::Client::new().get(url).send().await.unwrap_err();
+        assert_eq!(outcome_of(&error), AttemptOutcome::Tls(error.to_string()));
+    }
+
+    #[test]
+    fn content_range_with_total() {
+        assert_eq!(
+            content_range(&headers("bytes 0-499/1234")),
+            Some((0, Some(1234)))
+        );
+        assert_eq!(
+            content_range(&headers("bytes 500-1233/1234")),
+            Some((500, Some(1234)))
+        );
+    }
+
+    #[test]
+    fn content_range_with_unknown_total() {
+        assert_eq!(content_range(&headers("bytes 42-99/*")), Some((42, None)));
+    }
+
+    #[test]
+    fn content_range_invalid() {
+        assert_eq!(content_range(&reqwest::header::HeaderMap::new()), None);
+        assert_eq!(content_range(&headers("bytes */1234")), None);
+        assert_eq!(content_range(&headers("items 0-499/1234")), None);
+        assert_eq!(content_range(&headers("bytes 0-499")), None);
+        assert_eq!(content_range(&headers("bytes x-499/1234")), None);
+    }
+}
@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! Fetch one file as several byte ranges in parallel
+
+use crate::mirror::MirrorHealth;
+use crate::validators::Validators;
+use crate::{Attempt, AttemptOutcome, Download, DownloadSummary};
+
+use rand::seq::SliceRandom;
+
+use std::convert::TryFrom;
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// ----------------------------------------------------------------------
+// - Helper:
+// ----------------------------------------------------------------------
+
+/// What a server reported about a resource that supports range requests
+pub(super) struct Probe {
+    pub url: String,
+    pub total: u64,
+    pub validators: Validators,
+}
+
+/// State shared between all the ranges of one file
+pub(super) struct Shared<'a> {
+    pub context: &'a super::Context,
+    pub path: &'a std::path::Path,
+    pub probe: Probe,
+    pub transferred: AtomicU64,
+    pub progress: &'a crate::Progress,
+    pub status: std::sync::Mutex<Vec<Attempt>>,
+    pub mirrors: std::sync::Mutex<MirrorHealth>,
+}
+
+/// Ask `url` for its first byte to find out whether it supports range
+/// requests and how large the resource is.
+pub(super) async fn probe(
+    context: &super::Context,
+    download: &Download,
+    url: &str,
+) -> (Attempt, Option<Probe>) {
+    let _permit = context.host_limits.acquire(url).await;
+    let started = std::time::Instant::now();
+    let request = context
+        .limit_time(context.client.get(url))
+        .header(reqwest::header::RANGE, "bytes=0-0");
+    let response = match context.if_changed(url, request).send().await {
+        Ok(response) => response,
+        Err(e) => return (Attempt::failed(url, &e, started), None),
+    };
+
+    let attempt = Attempt::answered(url, &response, started);
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return (attempt, None);
+    }
+    if let Some(outcome) = super::unexpected_content(download, &response, 0) {
+        return (attempt.with_outcome(outcome), None);
+    }
+
+    let probe = match super::content_range(response.headers()) {
+        Some((0, Some(total))) if total > 0 => Some(Probe {
+            url: url.to_owned(),
+            total,
+            validators: Validators::from_response(&response),
+        }),
+        _ => None,
+    };
+    (attempt, probe)
+}
+
+/// Fetch the bytes in `range` from `url` into the shared file.
+///
+/// `done` is the number of bytes at the start of the `range` that are in
+/// the file already. It gets updated as data arrives. Data from other
+/// mirrors is only accepted if they report the size found by the probe.
+pub(super) async fn fetch_range(
+    shared: &Shared<'_>,
+    url: &str,
+    range: &std::ops::Range<u64>,
+    done: &mut u64,
+) -> Attempt {
+    let probe = &shared.probe;
+    let start = range.start + *done;
+    let _permit = shared.context.host_limits.acquire(url).await;
+    let started = std::time::Instant::now();
+    let mut request = shared
+        .context
+        .limit_time(shared.context.client.get(url))
+        .header(
+            reqwest::header::RANGE,
+            format!("bytes={}-{}", start, range.end - 1),
+        );
+    if url == probe.url {
+        if let Some(if_range) = probe.validators.if_range() {
+            request = request.header(reqwest::header::IF_RANGE, if_range);
+        }
+    }
+
+    let host_stats = &shared.context.host_stats;
+    let mut response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            host_stats.record_failure(url);
+            return Attempt::failed(url, &e, started);
+        }
+    };
+    host_stats.record_latency(url, started.elapsed());
+    let mut attempt = Attempt::answered(url, &response, started);
+    attempt.resumed = *done > 0;
+    let status = response.status();
+    if status != reqwest::StatusCode::PARTIAL_CONTENT {
+        host_stats.record_failure(url);
+        return attempt;
+    }
+    if super::content_range(response.headers()) != Some((start, Some(probe.total))) {
+        return attempt.with_outcome(AttemptOutcome::Status(
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE.as_u16(),
+        ));
+    }
+
+    let file = std::fs::OpenOptions::new().write(true).open(shared.path);
+    let mut writer = match file.map(std::io::BufWriter::new) {
+        Ok(writer) => writer,
+        Err(e) => return attempt.with_outcome(AttemptOutcome::Write(e.to_string())),
+    };
+    if let Err(e) = writer.seek(SeekFrom::Start(start)) {
+        return attempt.with_outcome(AttemptOutcome::Write(e.to_string()));
+    }
+
+    loop {
+        let bytes = match response.chunk().await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => break,
+            Err(e) => {
+                attempt = attempt.with_outcome(AttemptOutcome::Body(e.to_string()));
+                break;
+            }
+        };
+        shared.context.throttle(bytes.len() as u64).await;
+        let wanted = usize::try_from(range.end - range.start - *done).unwrap_or(usize::MAX);
+        let bytes = &bytes[..bytes.len().min(wanted)];
+        if let Err(e) = writer.write_all(bytes) {
+            attempt = attempt.with_outcome(AttemptOutcome::Write(e.to_string()));
+            break;
+        }
+
+        *done += bytes.len() as u64;
+        shared.progress.progress(
+            shared
+                .transferred
+                .fetch_add(bytes.len() as u64, Ordering::Relaxed)
+                + bytes.len() as u64,
+        );
+    }
+    if let Err(e) = writer.flush() {
+        *done = 0;
+        attempt = attempt.with_outcome(AttemptOutcome::Write(e.to_string()));
+    }
+
+    let skipped = start - range.start;
+    let received = done.saturating_sub(skipped);
+    let attempt = attempt.finished(received, started);
+    if *done == range.end - range.start {
+        host_stats.record_success(url, received, attempt.duration);
+        attempt
+    } else {
+        host_stats.record_failure(url);
+        if attempt.outcome.is_success() {
+            attempt.with_outcome(AttemptOutcome::Truncated {
+                expected: range.end - start,
+                received,
+            })
+        } else {
+            attempt
+        }
+    }
+}
+
+/// Probe the `urls` and set up `part_file` to receive the ranges.
+///
+/// Returns `None` if none of the servers supports range requests.
+pub(super) async fn prepare(
+    context: &super::Context,
+    download: &Download,
+    mirrors: &mut MirrorHealth,
+    part_file: &std::path::Path,
+    summary: &mut DownloadSummary,
+) -> Option<std::io::Result<Probe>> {
+    let mut candidates = mirrors.healthy().to_vec();
+    candidates.shuffle(&mut rand::thread_rng());
+
+    let mut found = None;
+    for url in candidates {
+        let (attempt, probe) = probe(context, download, &url).await;
+        summary.status.push(attempt.clone());
+        if context.is_unchanged(&attempt) {
+            summary.completion = crate::Completion::UpToDate;
+            return None;
+        }
+        super::drop_failed_mirror(mirrors, &url, &attempt);
+        if probe.is_some() {
+            found = probe;
+            break;
+        }
+    }
+    let probe = found?;
+    super::remember_validators(download, part_file, &probe.url, &probe.validators);
+
+    Some(
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(part_file)
+            .and_then(|file| file.set_len(probe.total))
+            .map(|()| probe),
+    )
+}
+
+async fn fetch_segment(
+    shared: &Shared<'_>,
+    download: &Download,
+    range: std::ops::Range<u64>,
+) -> std::io::Result<bool> {
+    let mut done = 0;
+    for attempt_number in 1..=shared.context.retries {
+        let healthy = shared.mirrors.lock().unwrap().healthy().to_vec();
+        if healthy.is_empty() {
+            break;
+        }
+        let url = super::select_url(shared.context, download, &healthy, attempt_number);
+        let attempt = fetch_range(shared, &url, &range, &mut done).await;
+        shared.status.lock().unwrap().push(attempt.clone());
+        super::write_error(&attempt)?;
+
+        if done == range.end - range.start {
+            return Ok(true);
+        }
+        let dropped =
+            super::drop_failed_mirror(&mut shared.mirrors.lock().unwrap(), &url, &attempt);
+        if !dropped
+            && attempt_number < shared.context.retries
+            && !shared.context.wait(attempt_number, &attempt).await
+        {
+            super::drop_busy_mirror(&mut shared.mirrors.lock().unwrap(), &url, &attempt);
+        }
+    }
+    Ok(false)
+}
+
+// ----------------------------------------------------------------------
+// - Entry point:
+// ----------------------------------------------------------------------
+
+/// Fetch `download` into `part_file` using `count` segments.
+///
+/// Returns `None` if the server can not do segmented downloads,
+/// otherwise whether the file was downloaded successfully or an error
+/// if writing the file failed.
+pub(super) async fn fetch(
+    context: &super::Context,
+    download: &Download,
+    part_file: &std::path::Path,
+    count: u16,
+    mirrors: &mut MirrorHealth,
+    summary: &mut DownloadSummary,
+    message: &mut String,
+) -> Option<std::io::Result<bool>> {
+    if count < 2 {
+        return None;
+    }
+
+    let probe = match prepare(context, download, mirrors, part_file, summary).await? {
+        Ok(probe) => probe,
+        Err(e) => return Some(Err(e)),
+    };
+
+    let progress = download.progress.as_ref().expect("This has been set!");
+    *message = format!(
+        "{} ({} segments)",
+        super::display_name(&summary.file_name),
+        count
+    );
+    progress.setup(Some(probe.total), message);
+
+    let total = probe.total;
+    let shared = Shared {
+        context,
+        path: part_file,
+        probe,
+        transferred: AtomicU64::new(0),
+        progress,
+        status: std::sync::Mutex::new(Vec::new()),
+        mirrors: std::sync::Mutex::new(std::mem::take(mirrors)),
+    };
+    let segment_size = total.div_ceil(u64::from(count));
+
+    let segments = (0..u64::from(count))
+        .map(|i| (i * segment_size)..((i + 1) * segment_size).min(total))
+        .filter(|r| !r.is_empty())
+        .map(|range| fetch_segment(&shared, download, range));
+    let results = futures::future::join_all(segments).await;
+
+    summary.status.extend(shared.status.into_inner().unwrap());
+    *mirrors = shared.mirrors.into_inner().unwrap();
+    progress.set_message(message);
+
+    Some(
+        results
+            .into_iter()
+            .collect::<std::io::Result<Vec<_>>>()
+            .map(|r| r.into_iter().all(|r| r)),
+    )
+}
@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! Hand the received data to the `Target` of a `Download`
+
+use crate::download::Target;
+use crate::verify::{Incremental, Streaming};
+use crate::Verification;
+
+use futures::SinkExt;
+use tokio::io::AsyncWriteExt;
+
+use std::convert::TryFrom;
+use std::io::{Seek, SeekFrom, Write};
+
+// ----------------------------------------------------------------------
+// - Sink:
+// ----------------------------------------------------------------------
+
+enum Kind {
+    File(std::io::BufWriter<std::fs::File>),
+    Writer(crate::download::Writer),
+    Memory(Vec<u8>),
+    Stream(Option<futures::channel::mpsc::Sender<bytes::Bytes>>),
+}
+
+/// The receiving end of a single (non-segmented) download
+///
+/// Files and memory buffers can be rewound when a server starts over,
+/// data passed on to writers and streams can not be taken back. Those
+/// skip the data they received already instead.
+pub(super) struct Sink {
+    kind: Kind,
+    /// The maximum number of bytes to accept
+    limit: Option<u64>,
+    /// The number of bytes passed on so far
+    delivered: u64,
+    factory: Option<Streaming>,
+    /// The verification fed with the data passed on so far
+    verifier: Option<Box<dyn Incremental>>,
+}
+
+impl Sink {
+    /// Create a `Sink` for `target`, using `part_file` for `Target::File`
+    pub(super) fn open(
+        target: &Target,
+        part_file: &std::path::Path,
+        resume: bool,
+        factory: Option<Streaming>,
+    ) -> std::io::Result<Self> {
+        let kind = match target {
+            Target::File => Kind::File(std::io::BufWriter::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(!resume)
+                    .write(true)
+                    .open(part_file)?,
+            )),
+            Target::Writer(writer) => Kind::Writer(writer.clone()),
+            Target::Memory(_) => Kind::Memory(Vec::new()),
+            Target::Stream(sender) => Kind::Stream(sender.take()),
+        };
+        let limit = match target {
+            Target::Memory(limit) => *limit,
+            _ => None,
+        };
+        Ok(Self {
+            kind,
+            limit,
+            delivered: 0,
+            verifier: factory.as_ref().map(|f| f()),
+            factory,
+        })
+    }
+
+    /// The maximum number of bytes to accept, if any
+    pub(super) const fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// The offset to continue downloading from
+    pub(super) fn resume_offset(&mut self) -> u64 {
+        match &mut self.kind {
+            Kind::File(writer) => {
+                if writer.flush().is_ok() {
+                    writer.get_ref().metadata().map_or(0, |m| m.len())
+                } else {
+                    0
+                }
+            }
+            _ => self.delivered,
+        }
+    }
+
+    /// Prepare to receive data starting at `start`
+    ///
+    /// Returns the number of bytes to skip as they were passed on already.
+    pub(super) fn rewind(&mut self, start: u64) -> std::io::Result<u64> {
+        match &mut self.kind {
+            Kind::File(writer) => {
+                writer.flush()?;
+                writer.get_ref().set_len(start)?;
+                writer.seek(SeekFrom::Start(start))?;
+            }
+            Kind::Memory(data) => {
+                data.truncate(usize::try_from(start).unwrap_or(usize::MAX));
+            }
+            Kind::Writer(_) | Kind::Stream(_) => {
+                return Ok(self.delivered.saturating_sub(start));
+            }
+        }
+        if self.delivered != start {
+            self.restart_verification(start);
+        }
+        self.delivered = start;
+        Ok(0)
+    }
+
+    /// Start verification over from the first `length` bytes
+    ///
+    /// This happens when a download resumes a file from an earlier run or
+    /// a server starts over sending the data. Reading back a partial file
+    /// would block, so its verification is left to be done once the file is
+    /// complete.
+    fn restart_verification(&mut self, length: u64) {
+        let Some(factory) = &self.factory else {
+            return;
+        };
+        self.verifier = match &self.kind {
+            Kind::File(_) if length > 0 => None,
+            Kind::Memory(data) => {
+                let mut verifier = factory();
+                verifier.update(data);
+                Some(verifier)
+            }
+            _ => Some(factory()),
+        };
+    }
+
+    /// Pass on `bytes`
+    pub(super) async fn write(&mut self, bytes: bytes::Bytes) -> std::io::Result<()> {
+        if let Some(verifier) = &mut self.verifier {
+            verifier.update(&bytes);
+        }
+        match &mut self.kind {
+            Kind::File(writer) => writer.write_all(&bytes)?,
+            Kind::Writer(writer) => writer.lock().await.write_all(&bytes).await?,
+            Kind::Memory(data) => data.extend_from_slice(&bytes),
+            Kind::Stream(sender) => {
+                let sender = sender.as_mut().ok_or_else(stream_closed)?;
+                let length = bytes.len() as u64;
+                sender.send(bytes).await.map_err(|_| stream_closed())?;
+                self.delivered += length;
+                return Ok(());
+            }
+        }
+        self.delivered += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Make sure all data passed on so far arrived
+    pub(super) async fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.kind {
+            Kind::File(writer) => writer.flush(),
+            Kind::Writer(writer) => writer.lock().await.flush().await,
+            Kind::Memory(_) | Kind::Stream(_) => Ok(()),
+        }
+    }
+
+    /// The result of verifying the data passed on and the data received
+    /// by a `Target::Memory`
+    ///
+    /// The result is `Verification::NotVerified` if a file needs to be
+    /// verified after the download.
+    pub(super) fn finish(self) -> (Verification, Option<Vec<u8>>) {
+        let verified = self
+            .verifier
+            .map_or(Verification::NotVerified, Incremental::finish);
+        match self.kind {
+            Kind::Memory(data) => (verified, Some(data)),
+            _ => (verified, None),
+        }
+    }
+}
+
+fn stream_closed() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "The receiving end of the stream is gone",
+    )
+}
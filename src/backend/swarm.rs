@@ -0,0 +1,248 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! Fetch chunks of one file from all mirrors at the same time
+
+use super::segmented::{self, Shared};
+use crate::mirror::MirrorHealth;
+use crate::{Attempt, AttemptOutcome, Download, DownloadSummary, DropReason};
+
+use std::sync::atomic::AtomicU64;
+
+/// Mirrors with less than 1/`SLOW_FACTOR` of the throughput of the fastest
+/// mirror get dropped.
+const SLOW_FACTOR: u128 = 10;
+
+// ----------------------------------------------------------------------
+// - Helper:
+// ----------------------------------------------------------------------
+
+#[derive(Default)]
+struct Mirror {
+    failures: u16,
+    /// Bytes per second of the last chunk
+    throughput: u128,
+}
+
+/// The work shared between all the mirrors
+///
+/// Whether a mirror got dropped is tracked in the `MirrorHealth` of the
+/// download only.
+struct Swarm {
+    /// Ranges that still need fetching
+    queue: std::collections::VecDeque<std::ops::Range<u64>>,
+    /// Ranges currently being fetched
+    in_flight: usize,
+    chunk_size: u64,
+    urls: Vec<String>,
+    mirrors: Vec<Mirror>,
+}
+
+impl Swarm {
+    /// Record the outcome of fetching a chunk from mirror `index`
+    ///
+    /// `throughput` is `None` for chunks too small to measure it.
+    /// Returns `true` if the mirror should get dropped.
+    fn update(
+        &mut self,
+        health: &MirrorHealth,
+        index: usize,
+        complete: bool,
+        retries: u16,
+        throughput: Option<u128>,
+    ) -> bool {
+        let others = health.healthy().len().saturating_sub(1);
+        let fastest = self
+            .urls
+            .iter()
+            .zip(&self.mirrors)
+            .filter(|(url, _)| health.is_healthy(url))
+            .map(|(_, m)| m.throughput)
+            .max()
+            .unwrap_or(0);
+
+        let mirror = &mut self.mirrors[index];
+        if complete {
+            throughput.is_some_and(|throughput| {
+                mirror.throughput = throughput;
+                others > 0 && throughput * SLOW_FACTOR < fastest
+            })
+        } else {
+            mirror.failures += 1;
+            others > 0 || mirror.failures >= retries
+        }
+    }
+}
+
+enum Work {
+    Fetch(std::ops::Range<u64>),
+    Wait,
+    Done,
+}
+
+fn next_work(shared: &Shared<'_>, swarm: &std::sync::Mutex<Swarm>, index: usize) -> Work {
+    let mut swarm = swarm.lock().unwrap();
+    if !shared
+        .mirrors
+        .lock()
+        .unwrap()
+        .is_healthy(&swarm.urls[index])
+    {
+        return Work::Done;
+    }
+    if let Some(range) = swarm.queue.pop_front() {
+        swarm.in_flight += 1;
+        Work::Fetch(range)
+    } else if swarm.in_flight > 0 {
+        // Another mirror might fail and put its chunk back
+        Work::Wait
+    } else {
+        Work::Done
+    }
+}
+
+fn drop_reason(attempt: &Attempt, complete: bool) -> DropReason {
+    if complete {
+        return DropReason::TooSlow;
+    }
+    attempt
+        .drop_reason()
+        .unwrap_or_else(|| match &attempt.outcome {
+            AttemptOutcome::Status(s) if !attempt.outcome.is_success() => DropReason::Status(*s),
+            AttemptOutcome::Body(message) => DropReason::Connection(message.clone()),
+            _ => DropReason::Connection(String::from("Transfer was incomplete")),
+        })
+}
+
+async fn run_mirror(
+    shared: &Shared<'_>,
+    swarm: &std::sync::Mutex<Swarm>,
+    changed: &tokio::sync::Notify,
+    index: usize,
+    retries: u16,
+) {
+    let url = swarm.lock().unwrap().urls[index].clone();
+    loop {
+        // Wake up for any change made after looking at the work:
+        let notified = changed.notified();
+        let range = match next_work(shared, swarm, index) {
+            Work::Fetch(range) => range,
+            Work::Wait => {
+                notified.await;
+                continue;
+            }
+            Work::Done => return,
+        };
+
+        let start = std::time::Instant::now();
+        let mut done = 0;
+        let attempt = segmented::fetch_range(shared, &url, &range, &mut done).await;
+        shared.status.lock().unwrap().push(attempt.clone());
+        if matches!(attempt.outcome, AttemptOutcome::Write(_)) {
+            // No mirror can help with that: Stop everything
+            let mut swarm = swarm.lock().unwrap();
+            swarm.in_flight -= 1;
+            swarm.queue.clear();
+            drop(swarm);
+            changed.notify_waiters();
+            return;
+        }
+
+        let complete = done == range.end - range.start;
+        let throughput = u128::from(done) * 1000 / start.elapsed().as_millis().max(1);
+
+        let (dropped, failures) = {
+            let mut swarm = swarm.lock().unwrap();
+            let mut health = shared.mirrors.lock().unwrap();
+            let throughput = (done == swarm.chunk_size).then_some(throughput);
+            swarm.in_flight -= 1;
+            if !complete {
+                swarm.queue.push_back((range.start + done)..range.end);
+            }
+            let dropped = swarm.update(&health, index, complete, retries, throughput);
+            if dropped {
+                health.drop_mirror(&url, drop_reason(&attempt, complete));
+            }
+            drop(health);
+            (dropped, swarm.mirrors[index].failures)
+        };
+        changed.notify_waiters();
+
+        if !complete && !dropped && !shared.context.wait(failures, &attempt).await {
+            super::drop_busy_mirror(&mut shared.mirrors.lock().unwrap(), &url, &attempt);
+        }
+    }
+}
+
+// ----------------------------------------------------------------------
+// - Entry point:
+// ----------------------------------------------------------------------
+
+/// Fetch `download` into `part_file` in chunks of `chunk_size` bytes,
+/// using all mirrors at the same time.
+///
+/// Returns `None` if the server can not do ranged downloads,
+/// otherwise whether the file was downloaded successfully or an error
+/// if writing the file failed.
+pub(super) async fn fetch(
+    context: &super::Context,
+    download: &Download,
+    part_file: &std::path::Path,
+    chunk_size: u64,
+    mirrors: &mut MirrorHealth,
+    summary: &mut DownloadSummary,
+    message: &mut String,
+) -> Option<std::io::Result<bool>> {
+    let probe = match segmented::prepare(context, download, mirrors, part_file, summary).await? {
+        Ok(probe) => probe,
+        Err(e) => return Some(Err(e)),
+    };
+    let urls = mirrors.healthy().to_vec();
+
+    let progress = download.progress.as_ref().expect("This has been set!");
+    *message = format!(
+        "{} ({} mirrors)",
+        super::display_name(&summary.file_name),
+        urls.len()
+    );
+    progress.setup(Some(probe.total), message);
+
+    let total = probe.total;
+    let shared = Shared {
+        context,
+        path: part_file,
+        probe,
+        transferred: AtomicU64::new(0),
+        progress,
+        status: std::sync::Mutex::new(Vec::new()),
+        mirrors: std::sync::Mutex::new(std::mem::take(mirrors)),
+    };
+    let mirror_count = urls.len();
+    let swarm = std::sync::Mutex::new(Swarm {
+        queue: (0..total.div_ceil(chunk_size))
+            .map(|i| (i * chunk_size)..((i + 1) * chunk_size).min(total))
+            .collect(),
+        in_flight: 0,
+        chunk_size,
+        mirrors: urls.iter().map(|_| Mirror::default()).collect(),
+        urls,
+    });
+    let changed = tokio::sync::Notify::new();
+
+    futures::future::join_all(
+        (0..mirror_count)
+            .map(|index| run_mirror(&shared, &swarm, &changed, index, context.retries)),
+    )
+    .await;
+
+    let status = shared.status.into_inner().unwrap();
+    let write_error = status.iter().find_map(|attempt| match &attempt.outcome {
+        AttemptOutcome::Write(message) => Some(std::io::Error::other(message.clone())),
+        _ => None,
+    });
+    summary.status.extend(status);
+    *mirrors = shared.mirrors.into_inner().unwrap();
+    progress.set_message(message);
+
+    Some(write_error.map_or_else(|| Ok(swarm.into_inner().unwrap().queue.is_empty()), Err))
+}
@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! A local cache of downloaded files shared between `Downloader`s
+
+// ----------------------------------------------------------------------
+// - Helper:
+// ----------------------------------------------------------------------
+
+/// The 64 bit FNV-1a hash of `key`
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn is_entry(path: &std::path::Path) -> bool {
+    path.file_name()
+        .and_then(std::ffi::OsStr::to_str)
+        .is_some_and(|n| n.len() == 16 && n.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// The file recording when `entry` was used last
+fn used_path(entry: &std::path::Path) -> std::path::PathBuf {
+    let mut path = entry.as_os_str().to_owned();
+    path.push(".used");
+    std::path::PathBuf::from(path)
+}
+
+/// Hard link `from` to `to`, falling back to copying
+fn link_or_copy(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    if std::fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to).map(|_| ())
+}
+
+// ----------------------------------------------------------------------
+// - Cache:
+// ----------------------------------------------------------------------
+
+/// A folder of downloaded files, named after the hash of their key
+///
+/// Entries are hard linked (or copied) in and out of the cache. The least
+/// recently used entries are removed once the cache grows beyond its
+/// maximum size. When an entry was used last is recorded in an empty
+/// `.used` file next to it: The entry itself is shared with the files
+/// linked to it and must not be touched.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    folder: std::path::PathBuf,
+    max_size: Option<u64>,
+}
+
+impl Cache {
+    pub const fn new(folder: std::path::PathBuf, max_size: Option<u64>) -> Self {
+        Self { folder, max_size }
+    }
+
+    fn entry(&self, key: &str) -> std::path::PathBuf {
+        self.folder.join(format!("{:016x}", fnv1a(key)))
+    }
+
+    /// Mark `entry` as used just now
+    fn touch(entry: &std::path::Path) {
+        _ = std::fs::write(used_path(entry), b"");
+    }
+
+    /// Put the data cached for `key` into `path`, replacing any file there
+    ///
+    /// Returns `false` if there is no such data.
+    pub fn fetch(&self, key: &str, path: &std::path::Path) -> bool {
+        let entry = self.entry(key);
+        if !entry.is_file() {
+            return false;
+        }
+        Self::touch(&entry);
+        _ = std::fs::remove_file(path);
+        link_or_copy(&entry, path).is_ok()
+    }
+
+    /// Cache the file at `path` for `key`
+    pub fn store(&self, key: &str, path: &std::path::Path) {
+        let entry = self.entry(key);
+        if entry.exists() {
+            return;
+        }
+        // Copies must not be visible before they are complete:
+        let mut temporary = entry.as_os_str().to_owned();
+        temporary.push(format!(".{:08x}", rand::random::<u32>()));
+        if link_or_copy(path, std::path::Path::new(&temporary)).is_ok() {
+            _ = std::fs::rename(&temporary, &entry);
+            Self::touch(&entry);
+        }
+        _ = std::fs::remove_file(&temporary);
+        self.evict(&entry);
+    }
+
+    /// Remove the data cached for `key`
+    pub fn remove(&self, key: &str) {
+        let entry = self.entry(key);
+        _ = std::fs::remove_file(&entry);
+        _ = std::fs::remove_file(used_path(&entry));
+    }
+
+    /// Remove the least recently used entries other than `keep` until the
+    /// cache fits its maximum size
+    fn evict(&self, keep: &std::path::Path) {
+        let Some(max_size) = self.max_size else {
+            return;
+        };
+        let Ok(dir) = std::fs::read_dir(&self.folder) else {
+            return;
+        };
+
+        let mut entries: Vec<_> = dir
+            .filter_map(std::result::Result::ok)
+            .filter(|e| is_entry(&e.path()) && e.path() != keep)
+            .filter_map(|e| {
+                let metadata = e.metadata().ok()?;
+                let used = std::fs::metadata(used_path(&e.path()))
+                    .and_then(|m| m.modified())
+                    .or_else(|_| metadata.modified())
+                    .ok()?;
+                Some((used, metadata.len(), e.path()))
+            })
+            .collect();
+        entries.sort();
+
+        let kept = std::fs::metadata(keep).map_or(0, |m| m.len());
+        let mut size: u64 = kept + entries.iter().map(|(_, len, _)| len).sum::<u64>();
+        for (_, len, path) in entries {
+            if size <= max_size {
+                break;
+            }
+            if std::fs::remove_file(&path).is_ok() {
+                _ = std::fs::remove_file(used_path(&path));
+                size -= len;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    /// An empty folder for the test `name`
+    fn folder(name: &str) -> std::path::PathBuf {
+        let folder = std::env::temp_dir().join(format!(
+            "downloader-cache-test-{}-{name}",
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    /// Store `size` bytes for `key` in `cache`, marked as used `age` ago
+    fn store(cache: &Cache, folder: &std::path::Path, key: &str, size: usize, age: u64) {
+        let source = folder.join(format!("source-{key}"));
+        std::fs::write(&source, vec![0_u8; size]).unwrap();
+        cache.store(key, &source);
+        std::fs::remove_file(&source).unwrap();
+        set_used(cache, key, age);
+    }
+
+    fn set_used(cache: &Cache, key: &str, age: u64) {
+        std::fs::File::options()
+            .write(true)
+            .open(used_path(&cache.entry(key)))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    fn modified(path: &std::path::Path) -> SystemTime {
+        std::fs::metadata(path).unwrap().modified().unwrap()
+    }
+
+    #[test]
+    fn store_and_fetch() {
+        let folder = folder("store_and_fetch");
+        let cache = Cache::new(folder.join("cache"), None);
+        std::fs::create_dir_all(folder.join("cache")).unwrap();
+        let target = folder.join("target");
+
+        assert!(!cache.fetch("key", &target));
+
+        std::fs::write(folder.join("source"), b"data").unwrap();
+        cache.store("key", &folder.join("source"));
+        std::fs::write(&target, b"old").unwrap();
+        assert!(cache.fetch("key", &target));
+        assert_eq!(std::fs::read(&target).unwrap(), b"data");
+        assert!(used_path(&cache.entry("key")).is_file());
+
+        cache.remove("key");
+        assert!(!cache.entry("key").exists());
+        assert!(!used_path(&cache.entry("key")).exists());
+        assert!(!cache.fetch("key", &folder.join("other")));
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn fetch_does_not_touch_entry() {
+        let folder = folder("fetch_does_not_touch_entry");
+        let cache = Cache::new(folder.clone(), None);
+        store(&cache, &folder, "key", 10, 3600);
+
+        let entry = cache.entry("key");
+        let then = SystemTime::now() - Duration::from_secs(7200);
+        std::fs::File::options()
+            .write(true)
+            .open(&entry)
+            .unwrap()
+            .set_modified(then)
+            .unwrap();
+
+        assert!(cache.fetch("key", &folder.join("target")));
+        assert_eq!(modified(&entry), then);
+        assert!(modified(&used_path(&entry)) > SystemTime::now() - Duration::from_secs(60));
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let folder = folder("evict_least_recently_used");
+        let cache = Cache::new(folder.clone(), Some(250));
+        store(&cache, &folder, "a", 100, 300);
+        store(&cache, &folder, "b", 100, 200);
+
+        // Using "a" makes "b" the least recently used entry:
+        assert!(cache.fetch("a", &folder.join("target")));
+        store(&cache, &folder, "c", 100, 100);
+
+        assert!(cache.entry("a").is_file());
+        assert!(!cache.entry("b").exists());
+        assert!(!used_path(&cache.entry("b")).exists());
+        assert!(cache.entry("c").is_file());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn evict_keeps_new_entry() {
+        let folder = folder("evict_keeps_new_entry");
+        let cache = Cache::new(folder.clone(), Some(50));
+        store(&cache, &folder, "a", 10, 300);
+        store(&cache, &folder, "b", 100, 0);
+
+        assert!(!cache.entry("a").exists());
+        assert!(cache.entry("b").is_file());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! Verify downloads against checksums given as strings or checksum files
+
+use crate::verify::{streaming_digest, streaming_named_digest, Streaming};
+use crate::{Download, Downloader, Error, Result};
+
+use base64::Engine;
+
+/// The largest checksum file that will be downloaded
+const MAX_CHECKSUM_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+// ----------------------------------------------------------------------
+// - Decoding:
+// ----------------------------------------------------------------------
+
+/// Decode a hash given as hex string
+///
+/// # Errors
+/// `Error::DownloadDefinition` if `hash` is not a valid hex string.
+pub fn decode_hex(hash: &str) -> Result<Vec<u8>> {
+    let hash = hash.trim();
+    let invalid = || Error::DownloadDefinition(format!("\"{hash}\" is not a valid hex string."));
+
+    if !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    (0..hash.len())
+        .step_by(2)
+        .map(|i| {
+            // A digit left over at the end of the string fails here
+            hash.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+/// Decode a hash given as base64 string
+///
+/// # Errors
+/// `Error::DownloadDefinition` if `hash` is not a valid base64 string.
+pub fn decode_base64(hash: &str) -> Result<Vec<u8>> {
+    let hash = hash.trim();
+    base64::engine::general_purpose::STANDARD
+        .decode(hash)
+        .or_else(|_| base64::engine::general_purpose::URL_SAFE.decode(hash))
+        .map_err(|_| Error::DownloadDefinition(format!("\"{hash}\" is not a valid base64 string.")))
+}
+
+// ----------------------------------------------------------------------
+// - Constructors:
+// ----------------------------------------------------------------------
+
+/// Verify the download against a `D` hash given as hex string
+///
+/// # Errors
+/// `Error::DownloadDefinition` if `hash` is not a valid hex string.
+pub fn hex<D: digest::Digest + Send + 'static>(hash: &str) -> Result<Streaming> {
+    Ok(streaming_digest::<D>(decode_hex(hash)?))
+}
+
+/// Verify the download against a `D` hash given as base64 string
+///
+/// # Errors
+/// `Error::DownloadDefinition` if `hash` is not a valid base64 string.
+pub fn base64<D: digest::Digest + Send + 'static>(hash: &str) -> Result<Streaming> {
+    Ok(streaming_digest::<D>(decode_base64(hash)?))
+}
+
+/// Verify the download against a Subresource Integrity string like
+/// `sha256-<base64 hash>`
+///
+/// `sha256`, `sha384` and `sha512` are supported. If `sri` lists several
+/// hashes, the strongest one is used.
+///
+/// # Errors
+/// `Error::DownloadDefinition` if `sri` does not contain a supported hash.
+pub fn sri(sri: &str) -> Result<Streaming> {
+    let (algorithm, hash) = sri
+        .split_whitespace()
+        .filter_map(|entry| {
+            let (algorithm, hash) = entry.split_once('-')?;
+            // Options follow a '?' and are ignored:
+            let hash = hash.split('?').next().unwrap_or(hash);
+            let strength = ["sha256", "sha384", "sha512"]
+                .iter()
+                .position(|a| *a == algorithm)?;
+            Some((strength, hash))
+        })
+        .max_by_key(|(strength, _)| *strength)
+        .ok_or_else(|| {
+            Error::DownloadDefinition(format!("\"{sri}\" contains no supported hash."))
+        })?;
+
+    let hash = decode_base64(hash)?;
+    Ok(match algorithm {
+        0 => streaming_named_digest::<sha2::Sha256>("sha256", hash),
+        1 => streaming_named_digest::<sha2::Sha384>("sha384", hash),
+        _ => streaming_named_digest::<sha2::Sha512>("sha512", hash),
+    })
+}
+
+// ----------------------------------------------------------------------
+// - ChecksumFile:
+// ----------------------------------------------------------------------
+
+/// The hashes listed in a checksum file like `SHA256SUMS` or `foo.sha512`
+///
+/// Both the GNU coreutils format (`<hex hash>  <file name>`, with an optional
+/// `*` in front of the file name) and the BSD format
+/// (`SHA256 (<file name>) = <hex hash>`) are understood. A line with just a
+/// hash applies to any file.
+#[derive(Clone, Debug, Default)]
+pub struct ChecksumFile {
+    hashes: std::collections::HashMap<String, Vec<u8>>,
+    any: Option<Vec<u8>>,
+}
+
+fn parse_line(line: &str) -> Option<(Option<&str>, &str)> {
+    if let Some((head, hash)) = line.rsplit_once(") = ") {
+        let (_, name) = head.split_once(" (")?;
+        return Some((Some(name), hash));
+    }
+    match line.split_once(char::is_whitespace) {
+        Some((hash, name)) => {
+            let name = name.trim_start();
+            Some((Some(name.strip_prefix('*').unwrap_or(name)), hash))
+        }
+        None => Some((None, line)),
+    }
+}
+
+impl ChecksumFile {
+    /// Parse the `contents` of a checksum file
+    ///
+    /// # Errors
+    /// `Error::DownloadDefinition` if a line can not be parsed.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut result = Self::default();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, hash) = parse_line(line).ok_or_else(|| {
+                Error::DownloadDefinition(format!("Invalid checksum line \"{line}\"."))
+            })?;
+            let hash = decode_hex(hash)?;
+            match name {
+                Some(name) => {
+                    result.hashes.insert(name.to_owned(), hash);
+                }
+                None => result.any = Some(hash),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Download the checksum file at `url` with `downloader` and parse it
+    ///
+    /// # Errors
+    /// The error of the download if the file could not be downloaded,
+    /// `Error::DownloadDefinition` if it could not be parsed.
+    pub fn fetch(downloader: &mut Downloader, url: &str) -> Result<Self> {
+        let data = downloader.fetch_small_file(url, MAX_CHECKSUM_FILE_SIZE)?;
+        Self::parse(&String::from_utf8_lossy(&data))
+    }
+
+    /// Download the checksum file at `url` with `downloader` and parse it
+    ///
+    /// # Errors
+    /// The error of the download if the file could not be downloaded,
+    /// `Error::DownloadDefinition` if it could not be parsed.
+    pub async fn async_fetch(downloader: &mut Downloader, url: &str) -> Result<Self> {
+        let data = downloader
+            .async_fetch_small_file(url, MAX_CHECKSUM_FILE_SIZE)
+            .await?;
+        Self::parse(&String::from_utf8_lossy(&data))
+    }
+
+    /// The hash listed for `file_name`
+    ///
+    /// Only the last component of `file_name` is used for the look up.
+    #[must_use]
+    pub fn get(&self, file_name: &std::path::Path) -> Option<&[u8]> {
+        file_name
+            .file_name()
+            .and_then(|n| self.hashes.get(n.to_string_lossy().as_ref()))
+            .or(self.any.as_ref())
+            .map(Vec::as_slice)
+    }
+
+    /// Set up `download` to be verified against the `D` hash listed for its
+    /// `file_name`
+    ///
+    /// # Errors
+    /// `Error::DownloadDefinition` if there is no hash for the `download`.
+    pub fn verify<D: digest::Digest + Send + 'static>(
+        &self,
+        download: Download,
+    ) -> Result<Download> {
+        let hash = self.get(&download.file_name).ok_or_else(|| {
+            Error::DownloadDefinition(format!(
+                "No checksum found for \"{}\".",
+                download.file_name.to_string_lossy()
+            ))
+        })?;
+        let streaming = streaming_digest::<D>(hash.to_vec());
+        Ok(download.verify_streaming(streaming))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::verify::Verification;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn check(streaming: &Streaming, data: &[u8]) -> Verification {
+        let mut verifier = streaming();
+        verifier.update(data);
+        verifier.finish()
+    }
+
+    fn sri_hash<D: digest::Digest>(data: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(D::digest(data))
+    }
+
+    #[test]
+    fn decode_hex_accepts_both_cases() {
+        assert_eq!(decode_hex(" 00fFa0 ").unwrap(), vec![0x00, 0xff, 0xa0]);
+        assert_eq!(decode_hex("").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn decode_hex_rejects_bad_hex() {
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("0g").is_err());
+        assert!(decode_hex("+f").is_err());
+        assert!(decode_hex("ä0").is_err());
+    }
+
+    #[test]
+    fn decode_base64_accepts_both_alphabets() {
+        assert_eq!(decode_base64("+/8=").unwrap(), vec![0xfb, 0xff]);
+        assert_eq!(decode_base64("-_8=").unwrap(), vec![0xfb, 0xff]);
+        assert!(decode_base64("not base64!").is_err());
+    }
+
+    #[test]
+    fn parse_line_formats() {
+        assert_eq!(
+            parse_line("abcd  foo.tar.gz"),
+            Some((Some("foo.tar.gz"), "abcd"))
+        );
+        assert_eq!(
+            parse_line("abcd *foo.tar.gz"),
+            Some((Some("foo.tar.gz"), "abcd"))
+        );
+        assert_eq!(
+            parse_line("abcd  name with spaces"),
+            Some((Some("name with spaces"), "abcd"))
+        );
+        assert_eq!(
+            parse_line("SHA256 (foo.tar.gz) = abcd"),
+            Some((Some("foo.tar.gz"), "abcd"))
+        );
+        assert_eq!(parse_line("abcd"), Some((None, "abcd")));
+    }
+
+    #[test]
+    fn parse_checksum_file() {
+        let contents =
+            format!("# A comment\n\n{ABC_SHA256}  a.txt\n00ff *b.bin\nSHA256 (c d.txt) = 0102\n");
+        let file = ChecksumFile::parse(&contents).unwrap();
+
+        assert_eq!(
+            file.get(std::path::Path::new("a.txt")).unwrap(),
+            decode_hex(ABC_SHA256).unwrap()
+        );
+        assert_eq!(
+            file.get(std::path::Path::new("some/folder/b.bin")).unwrap(),
+            &[0x00, 0xff]
+        );
+        assert_eq!(
+            file.get(std::path::Path::new("c d.txt")).unwrap(),
+            &[0x01, 0x02]
+        );
+        assert!(file.get(std::path::Path::new("d.txt")).is_none());
+    }
+
+    #[test]
+    fn parse_checksum_file_with_bare_hash() {
+        let file = ChecksumFile::parse(&format!("{ABC_SHA256}\n")).unwrap();
+        assert_eq!(
+            file.get(std::path::Path::new("anything")).unwrap(),
+            decode_hex(ABC_SHA256).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_checksum_file_with_bad_hex() {
+        assert!(ChecksumFile::parse("xyz  a.txt\n").is_err());
+        assert!(ChecksumFile::parse("abc  a.txt\n").is_err());
+    }
+
+    #[test]
+    fn hex_verifies_data() {
+        let streaming = hex::<sha2::Sha256>(ABC_SHA256).unwrap();
+        assert_eq!(check(&streaming, b"abc"), Verification::Ok);
+        assert!(check(&streaming, b"abd").is_failed());
+    }
+
+    #[test]
+    fn sri_picks_strongest_hash() {
+        // The sha256 hash matches, the stronger sha512 one does not:
+        let sri = format!(
+            "sha256-{} sha512-{}?some-option",
+            sri_hash::<sha2::Sha256>(b"abc"),
+            sri_hash::<sha2::Sha512>(b"abd")
+        );
+        let streaming = super::sri(&sri).unwrap();
+
+        assert_eq!(
+            check(&streaming, b"abd"),
+            Verification::Ok,
+            "sha512 hash was not used"
+        );
+        assert!(check(&streaming, b"abc")
+            .to_string()
+            .starts_with("FAILED: sha512 mismatch"));
+    }
+
+    #[test]
+    fn sri_without_supported_hash() {
+        assert!(super::sri("md5-AAAA sha1-AAAA").is_err());
+        assert!(super::sri("").is_err());
+    }
+}
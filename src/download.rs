@@ -4,21 +4,181 @@
 //! The `Download` struct is used to describe a file that is
 //! supposed to get downloaded.
 
+// ----------------------------------------------------------------------
+// - Transfer:
+// ----------------------------------------------------------------------
+
+/// The way the data of a `Download` is transferred.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transfer {
+    /// Fetch the file in one request.
+    Single,
+    /// Split the file into this many byte ranges and fetch them concurrently.
+    ///
+    /// This falls back to `Single` when the server does not support range
+    /// requests or does not report the size of the file.
+    Segmented(u16),
+    /// Split the file into chunks of this many bytes and fetch them from all
+    /// mirrors at the same time.
+    ///
+    /// Faster mirrors will end up serving more chunks, mirrors that fail or
+    /// are much slower than the others are dropped. This falls back to
+    /// `Single` just like `Segmented` does.
+    Swarm(u64),
+}
+
+// ----------------------------------------------------------------------
+// - Target:
+// ----------------------------------------------------------------------
+
+/// An `AsyncWrite` to download into
+///
+/// Keep a clone of this around to get the writer back after the download.
+pub type Writer = std::sync::Arc<tokio::sync::Mutex<dyn tokio::io::AsyncWrite + Send + Unpin>>;
+
+/// The data of a `Download` with a `Target::Stream`
+///
+/// The stream ends when the download is done. Check the result of the
+/// download to find out whether all data was received.
+pub type ByteStream = futures::channel::mpsc::Receiver<bytes::Bytes>;
+
+/// The sending side of a `ByteStream`
+#[derive(Clone)]
+pub struct StreamSender {
+    sender: std::sync::Arc<std::sync::Mutex<Option<futures::channel::mpsc::Sender<bytes::Bytes>>>>,
+}
+
+impl StreamSender {
+    /// Take the sender out, so that the stream ends once it is dropped.
+    pub(crate) fn take(&self) -> Option<futures::channel::mpsc::Sender<bytes::Bytes>> {
+        self.sender.lock().ok().and_then(|mut s| s.take())
+    }
+}
+
+/// Where the data of a `Download` goes.
+#[derive(Clone)]
+pub enum Target {
+    /// Write into `file_name` in the `download_folder`.
+    File,
+    /// Write into an `AsyncWrite`.
+    Writer(Writer),
+    /// Keep the data in memory and hand it out in `DownloadSummary::data`.
+    ///
+    /// The download fails if the data is larger than the given number of
+    /// bytes.
+    Memory(Option<u64>),
+    /// Send the data to a `ByteStream`.
+    Stream(StreamSender),
+}
+
+impl Target {
+    /// Create a `Target` writing into `writer`
+    pub fn writer(writer: impl tokio::io::AsyncWrite + Send + Unpin + 'static) -> Self {
+        Self::Writer(std::sync::Arc::new(tokio::sync::Mutex::new(writer)))
+    }
+
+    /// Create a `Target` sending the data into the returned `ByteStream`
+    ///
+    /// The stream needs to be consumed while the download is running, so
+    /// use `Downloader::async_download` or read it from another thread.
+    #[must_use]
+    pub fn stream() -> (Self, ByteStream) {
+        let (sender, receiver) = futures::channel::mpsc::channel(16);
+        (
+            Self::Stream(StreamSender {
+                sender: std::sync::Arc::new(std::sync::Mutex::new(Some(sender))),
+            }),
+            receiver,
+        )
+    }
+
+    /// Whether the data goes into a file
+    #[must_use]
+    pub const fn is_file(&self) -> bool {
+        matches!(self, Self::File)
+    }
+}
+
+// ----------------------------------------------------------------------
+// - ExistingFilePolicy:
+// ----------------------------------------------------------------------
+
+/// What to do when the file of a `Download` exists already.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ExistingFilePolicy {
+    /// Fail the download with `Error::FileExists`.
+    #[default]
+    Fail,
+    /// Download the file again and replace the existing one.
+    Overwrite,
+    /// Keep the existing file without downloading anything.
+    Skip,
+    /// Keep the existing file if it passes verification and download it
+    /// again otherwise.
+    ///
+    /// Files are always downloaded again if the `Download` has no way to
+    /// verify them.
+    SkipIfVerified,
+    /// Download the file again only if the server reports that the resource
+    /// changed since the existing file was downloaded.
+    ///
+    /// The `ETag`, `Last-Modified` and size of downloaded files are kept in
+    /// a `.validators` file next to them for this. Files without that
+    /// information or that were changed locally are downloaded again.
+    Update,
+    /// Treat the existing file as a partial download and continue it.
+    ///
+    /// There is no way to make sure the existing data belongs to the same
+    /// resource, so verify the download to catch mismatches. This can not
+    /// be used with segmented or swarm downloads.
+    Resume,
+}
+
 // ----------------------------------------------------------------------
 // - Download:
 // ----------------------------------------------------------------------
 
 /// A `Download`.
+#[derive(Clone)]
 pub struct Download {
     /// A list of URLs that this file can be retrieved from. `downloader` will pick
-    /// the download URL from this list at random.
+    /// the download URL from this list using the `mirror_selector`.
     pub urls: Vec<String>,
     /// A progress `Reporter` to report the download process with.
     pub progress: Option<crate::Progress>,
     /// The file name to be used for the downloaded file.
     pub file_name: std::path::PathBuf,
+    /// Where the downloaded data goes.
+    pub target: Target,
     /// A callback used to verify the download with.
-    pub verify_callback: crate::Verify,
+    pub verify_callback: Option<crate::Verify>,
+    /// An async callback used to verify the download with.
+    pub async_verify_callback: Option<crate::AsyncVerify>,
+    /// Verification fed with the data while it is downloaded.
+    pub streaming_verify: Option<crate::verify::Streaming>,
+    /// Download the data again from another mirror when verification fails.
+    pub retry_verification: bool,
+    /// Resume a partial download left over by an earlier run instead of
+    /// starting from scratch.
+    pub resume: bool,
+    /// What to do when `file_name` exists already. The `Downloader`
+    /// provides this if it is unset.
+    pub existing_file: Option<ExistingFilePolicy>,
+    /// The key to store the data under in the cache of the `Downloader`.
+    /// The first URL is used if this is unset.
+    pub cache_key: Option<String>,
+    /// How to transfer the data.
+    pub transfer: Transfer,
+    /// The size of the data in bytes, if known in advance.
+    pub expected_size: Option<u64>,
+    /// The content types the data may have. Any type is accepted if this
+    /// is empty.
+    pub content_types: Vec<String>,
+    /// The maximum rate to receive the data at in bytes per second.
+    pub max_bandwidth: Option<u64>,
+    /// The strategy used to pick a URL out of `urls`. The `Downloader`
+    /// provides one if this is unset.
+    pub mirror_selector: Option<crate::mirror::Selector>,
 }
 
 fn file_name_from_url(url: &str) -> std::path::PathBuf {
@@ -30,8 +190,8 @@ fn file_name_from_url(url: &str) -> std::path::PathBuf {
     };
 
     url.path_segments()
-        .map_or_else(std::path::PathBuf::new, |f| {
-            std::path::PathBuf::from(f.last().unwrap_or(""))
+        .map_or_else(std::path::PathBuf::new, |mut f| {
+            std::path::PathBuf::from(f.next_back().unwrap_or(""))
         })
 }
 
@@ -43,7 +203,19 @@ impl Download {
             urls: vec![url.to_owned()],
             progress: None,
             file_name: file_name_from_url(url),
-            verify_callback: crate::verify::noop(),
+            target: Target::File,
+            verify_callback: None,
+            async_verify_callback: None,
+            streaming_verify: None,
+            retry_verification: false,
+            resume: false,
+            existing_file: None,
+            cache_key: None,
+            transfer: Transfer::Single,
+            expected_size: None,
+            content_types: Vec::new(),
+            max_bandwidth: None,
+            mirror_selector: None,
         }
     }
 
@@ -57,7 +229,19 @@ impl Download {
             urls,
             progress: None,
             file_name: file_name_from_url(&url),
-            verify_callback: crate::verify::noop(),
+            target: Target::File,
+            verify_callback: None,
+            async_verify_callback: None,
+            streaming_verify: None,
+            retry_verification: false,
+            resume: false,
+            existing_file: None,
+            cache_key: None,
+            transfer: Transfer::Single,
+            expected_size: None,
+            content_types: Vec::new(),
+            max_bandwidth: None,
+            mirror_selector: None,
         }
     }
 
@@ -71,6 +255,30 @@ impl Download {
         self
     }
 
+    /// Set where the downloaded data goes
+    ///
+    /// Only `Target::File` supports `resume`, segmented and swarm transfers
+    /// and the `verify` and `verify_async` callbacks. Use `verify_streaming`
+    /// to verify the data of other targets. For all other targets
+    /// `file_name` is only used to identify the download.
+    ///
+    /// Default is to write into `file_name`.
+    #[must_use]
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Keep the downloaded data in memory instead of writing a file
+    ///
+    /// The data ends up in `DownloadSummary::data`. The download fails
+    /// without retrying if the data is larger than `max_size` bytes.
+    #[must_use]
+    pub fn in_memory(mut self, max_size: u64) -> Self {
+        self.target = Target::Memory(Some(max_size));
+        self
+    }
+
     /// Register handling of progress information
     ///
     /// Defaults to not printing any progress information.
@@ -82,10 +290,166 @@ impl Download {
 
     /// Register a callback to verify a download
     ///
+    /// This reads the downloaded file, so it only works with `Target::File`.
+    /// Use `verify_streaming` for other targets.
+    ///
     /// Default is to assume the file was downloaded correctly.
     #[must_use]
     pub fn verify(mut self, func: crate::Verify) -> Self {
-        self.verify_callback = func;
+        self.verify_callback = Some(func);
+        self
+    }
+
+    /// Register an async callback to verify a download
+    ///
+    /// This runs after the `verify` callback, unless that one failed
+    /// already. Use `verify` for CPU-bound checks like hashing, which would
+    /// block the async runtime. Just like `verify` this only works with
+    /// `Target::File`.
+    ///
+    /// Default is to assume the file was downloaded correctly.
+    #[must_use]
+    pub fn verify_async(mut self, func: crate::AsyncVerify) -> Self {
+        self.async_verify_callback = Some(func);
+        self
+    }
+
+    /// Verify a download while the data comes in
+    ///
+    /// This saves reading the file again after the download, unless the
+    /// file was fetched in segments or parts of it were downloaded in an
+    /// earlier run. Unlike `verify` this works for all `Target`s.
+    ///
+    /// The download fails if either of the verifications fails. Default is
+    /// to not verify the data while downloading.
+    #[must_use]
+    pub fn verify_streaming(mut self, factory: crate::verify::Streaming) -> Self {
+        self.streaming_verify = Some(factory);
+        self
+    }
+
+    /// Download the data again from another mirror when it fails verification
+    ///
+    /// The data is discarded and the mirrors that served it are dropped.
+    /// The download fails once no mirrors are left. Data passed on to
+    /// `Target::Writer` and `Target::Stream` can not be taken back, so those
+    /// downloads are never retried.
+    ///
+    /// Default is to fail the download when verification fails.
+    #[must_use]
+    pub const fn retry_verification(mut self, retry: bool) -> Self {
+        self.retry_verification = retry;
+        self
+    }
+
+    /// Resume a partially downloaded file
+    ///
+    /// Downloads are written into a `.part` file next to `file_name` first.
+    /// With `resume` set, such a file is kept when the download fails and
+    /// the next download will pick up where it left off, provided the server
+    /// supports range requests and the resource did not change in the
+    /// meantime. Otherwise the file is downloaded in full. This can not be
+    /// used with segmented or swarm downloads.
+    ///
+    /// Default is to remove the `.part` file after failed downloads.
+    #[must_use]
+    pub const fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Set what to do when `file_name` exists already
+    ///
+    /// Default is to use the policy set up in the `Downloader`.
+    #[must_use]
+    pub const fn existing_file(mut self, policy: ExistingFilePolicy) -> Self {
+        self.existing_file = Some(policy);
+        self
+    }
+
+    /// Set the key to store the data under in the cache of the `Downloader`
+    ///
+    /// Use something that identifies the content, like its hash, to share
+    /// cache entries between different URLs.
+    ///
+    /// Default is to use the first URL.
+    #[must_use]
+    pub fn cache_key(mut self, key: &str) -> Self {
+        self.cache_key = Some(key.to_owned());
+        self
+    }
+
+    /// Expect the data to be exactly `size` bytes long
+    ///
+    /// Servers announcing or sending a different size are dropped as
+    /// mirrors without downloading more data than necessary.
+    ///
+    /// Default is to accept data of any size.
+    #[must_use]
+    pub const fn expected_size(mut self, size: u64) -> Self {
+        self.expected_size = Some(size);
+        self
+    }
+
+    /// Accept data with the `content_type` only
+    ///
+    /// This can be called several times to accept more than one type.
+    /// Parameters like `charset` are ignored and a `*` subtype matches
+    /// all subtypes (e.g. `text/*`). Servers that send a different or no
+    /// content type are dropped as mirrors.
+    ///
+    /// Default is to accept any content type.
+    #[must_use]
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_types.push(content_type.to_lowercase());
+        self
+    }
+
+    /// Receive the data at `bytes_per_second` at most
+    ///
+    /// This applies on top of the bandwidth limit of the `Downloader`.
+    ///
+    /// The timeout of the `Downloader` applies to each read of the response
+    /// then, not to the whole request.
+    ///
+    /// Default is to not limit the bandwidth.
+    #[must_use]
+    pub const fn max_bandwidth(mut self, bytes_per_second: u64) -> Self {
+        self.max_bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    /// Set the strategy used to pick the mirror to download from
+    ///
+    /// Default is to use the strategy set up in the `Downloader`.
+    #[must_use]
+    pub fn mirror_selector(mut self, selector: crate::mirror::Selector) -> Self {
+        self.mirror_selector = Some(selector);
+        self
+    }
+
+    /// Fetch the file in `count` segments in parallel
+    ///
+    /// Each segment is fetched from one of the mirrors in `urls`. Partial
+    /// `.part` files can not be resumed in this mode, so this can not be
+    /// combined with `resume` or `ExistingFilePolicy::Resume`.
+    ///
+    /// Default is to fetch the file with one request.
+    #[must_use]
+    pub const fn segmented(mut self, count: u16) -> Self {
+        self.transfer = Transfer::Segmented(count);
+        self
+    }
+
+    /// Fetch the file in chunks of `chunk_size` bytes from all mirrors at once
+    ///
+    /// Partial `.part` files can not be resumed in this mode, so this can
+    /// not be combined with `resume` or `ExistingFilePolicy::Resume`.
+    ///
+    /// Default is to fetch the file with one request.
+    #[must_use]
+    pub const fn swarm(mut self, chunk_size: u64) -> Self {
+        self.transfer = Transfer::Swarm(chunk_size);
         self
     }
 }
@@ -5,6 +5,7 @@
 
 use crate::{Download, DownloadSummary, Error, Result};
 
+use crate::download::ExistingFilePolicy;
 use crate::progress::Factory;
 
 // ----------------------------------------------------------------------
@@ -15,6 +16,8 @@ fn validate_downloads(
     downloads: &[Download],
     download_folder: &std::path::Path,
     factory: &dyn Factory,
+    mirror_selector: &crate::mirror::Selector,
+    existing_file: ExistingFilePolicy,
 ) -> Result<Vec<Download>> {
     let mut known_urls = std::collections::HashSet::new();
     let mut known_download_paths = std::collections::HashSet::new();
@@ -36,45 +39,87 @@ fn validate_downloads(
             }
         }
 
-        let urls = d.urls.clone();
-
-        if d.file_name.to_string_lossy().is_empty() {
+        let is_file = d.target.is_file();
+        if is_file && d.file_name.to_string_lossy().is_empty() {
             return Err(Error::DownloadDefinition(String::from(
                 "No download file name was provided.",
             )));
         }
 
         let file_name = download_folder.join(&d.file_name);
-        if d.file_name.to_string_lossy().is_empty() {
+        if is_file && d.file_name.to_string_lossy().is_empty() {
             return Err(Error::DownloadDefinition(String::from(
                 "Failed to get full download path.",
             )));
         }
 
-        if !known_download_paths.insert(&d.file_name) {
+        if is_file && !known_download_paths.insert(&d.file_name) {
             return Err(Error::DownloadDefinition(format!(
                 "Download file name \"{}\" is used more than once.",
                 d.file_name.to_string_lossy(),
             )));
         }
 
-        let progress = if d.progress.is_none() {
-            factory.create_reporter()
-        } else {
-            d.progress.as_ref().expect("Was Some just now...").clone()
-        };
+        if d.transfer == crate::download::Transfer::Segmented(0) {
+            return Err(Error::DownloadDefinition(String::from(
+                "Segmented download needs at least one segment.",
+            )));
+        }
+        if d.transfer == crate::download::Transfer::Swarm(0) {
+            return Err(Error::DownloadDefinition(String::from(
+                "Swarm download needs a chunk size.",
+            )));
+        }
+        if !is_file && d.transfer != crate::download::Transfer::Single {
+            return Err(Error::DownloadDefinition(String::from(
+                "Segmented and swarm downloads need a file to write into.",
+            )));
+        }
+        if !is_file && (d.verify_callback.is_some() || d.async_verify_callback.is_some()) {
+            return Err(Error::DownloadDefinition(String::from(
+                "Only downloads into files can be verified after the download, use verify_streaming instead.",
+            )));
+        }
+        let existing_file = d.existing_file.unwrap_or(existing_file);
+        if (d.resume || existing_file == ExistingFilePolicy::Resume)
+            && d.transfer != crate::download::Transfer::Single
+        {
+            return Err(Error::DownloadDefinition(String::from(
+                "Segmented and swarm downloads can not be resumed.",
+            )));
+        }
 
-        result.push(Download {
-            urls,
-            file_name,
-            progress: Some(progress),
-            verify_callback: d.verify_callback.clone(),
-        });
+        let progress = d
+            .progress
+            .as_ref()
+            .map_or_else(|| factory.create_reporter(), Clone::clone);
+
+        let mut download = d.clone();
+        download.file_name = file_name;
+        download.progress = Some(progress);
+        download.mirror_selector = Some(
+            d.mirror_selector
+                .as_ref()
+                .unwrap_or(mirror_selector)
+                .clone(),
+        );
+        download.existing_file = Some(existing_file);
+        result.push(download);
     }
 
     Ok(result)
 }
 
+/// The data of the single in-memory download that produced `summaries`
+#[cfg(any(feature = "verify", feature = "signature"))]
+fn data_of(summaries: Vec<Result<DownloadSummary>>) -> Result<Vec<u8>> {
+    let summary = summaries
+        .into_iter()
+        .next()
+        .expect("One download was requested")?;
+    Ok(summary.data.unwrap_or_default())
+}
+
 // ----------------------------------------------------------------------
 // - Downloader:
 // ----------------------------------------------------------------------
@@ -83,9 +128,18 @@ fn validate_downloads(
 /// `download` on that, passing in a list of `Download` objects.
 pub struct Downloader {
     client: reqwest::Client,
+    /// The time a whole request may take, `None` if `client` takes care of it
+    timeout: Option<std::time::Duration>,
     parallel_requests: u16,
     retries: u16,
+    retry_policy: crate::RetryPolicy,
     download_folder: std::path::PathBuf,
+    mirror_selector: crate::mirror::Selector,
+    existing_file: ExistingFilePolicy,
+    cache: Option<crate::cache::Cache>,
+    host_stats: crate::mirror::HostStats,
+    host_limits: crate::throttle::HostLimits,
+    bandwidth: Option<crate::throttle::Bandwidth>,
 }
 
 impl Downloader {
@@ -95,6 +149,27 @@ impl Downloader {
         Builder::default()
     }
 
+    /// Performance data collected on the hosts downloaded from so far
+    #[must_use]
+    pub const fn host_stats(&self) -> &crate::mirror::HostStats {
+        &self.host_stats
+    }
+
+    fn context(&self) -> crate::backend::Context {
+        crate::backend::Context {
+            client: self.client.clone(),
+            timeout: self.timeout,
+            retries: self.retries,
+            retry_policy: self.retry_policy,
+            host_stats: self.host_stats.clone(),
+            host_limits: self.host_limits.clone(),
+            bandwidth: self.bandwidth.clone(),
+            download_bandwidth: None,
+            conditions: None,
+            cache: self.cache.clone(),
+        }
+    }
+
     /// Start the download
     ///
     /// # Errors
@@ -105,15 +180,20 @@ impl Downloader {
         #[cfg(not(feature = "tui"))]
         let factory = crate::progress::Noop::default();
 
-        let to_process = validate_downloads(downloads, &self.download_folder, &factory)?;
+        let to_process = validate_downloads(
+            downloads,
+            &self.download_folder,
+            &factory,
+            &self.mirror_selector,
+            self.existing_file,
+        )?;
         if to_process.is_empty() {
             return Ok(Vec::new());
         }
 
         Ok(crate::backend::run(
-            &mut self.client,
+            self.context(),
             to_process,
-            self.retries,
             self.parallel_requests,
         ))
     }
@@ -131,21 +211,44 @@ impl Downloader {
         #[cfg(not(feature = "tui"))]
         let factory = crate::progress::Noop::default();
 
-        let to_process = validate_downloads(downloads, &self.download_folder, &factory)?;
+        let to_process = validate_downloads(
+            downloads,
+            &self.download_folder,
+            &factory,
+            &self.mirror_selector,
+            self.existing_file,
+        )?;
         if to_process.is_empty() {
             return Ok(Vec::new());
         }
 
-        let result = crate::backend::async_run(
-            &mut self.client,
-            to_process,
-            self.retries,
-            self.parallel_requests,
-        )
-        .await;
+        let result =
+            crate::backend::async_run(self.context(), to_process, self.parallel_requests).await;
 
         Ok(result)
     }
+
+    /// Download the file at `url` into memory, failing if it is larger
+    /// than `max_size` bytes
+    #[cfg(any(feature = "verify", feature = "signature"))]
+    pub(crate) fn fetch_small_file(&mut self, url: &str, max_size: u64) -> Result<Vec<u8>> {
+        let summaries = self.download(&[Download::new(url).in_memory(max_size)])?;
+        data_of(summaries)
+    }
+
+    /// Download the file at `url` into memory, failing if it is larger
+    /// than `max_size` bytes
+    #[cfg(any(feature = "verify", feature = "signature"))]
+    pub(crate) async fn async_fetch_small_file(
+        &mut self,
+        url: &str,
+        max_size: u64,
+    ) -> Result<Vec<u8>> {
+        let summaries = self
+            .async_download(&[Download::new(url).in_memory(max_size)])
+            .await?;
+        data_of(summaries)
+    }
 }
 
 // ----------------------------------------------------------------------
@@ -158,8 +261,16 @@ pub struct Builder {
     connect_timeout: std::time::Duration,
     timeout: std::time::Duration,
     parallel_requests: u16,
+    connections_per_host: Option<u16>,
+    host_delay: std::time::Duration,
+    max_bandwidth: Option<u64>,
     retries: u16,
+    retry_policy: crate::RetryPolicy,
     download_folder: std::path::PathBuf,
+    mirror_selector: crate::mirror::Selector,
+    existing_file: ExistingFilePolicy,
+    cache_folder: Option<std::path::PathBuf>,
+    cache_size: Option<u64>,
 }
 
 impl Builder {
@@ -181,6 +292,10 @@ impl Builder {
 
     /// Set the timeout.
     ///
+    /// This covers the whole request, unless the download has a maximum
+    /// bandwidth: Throttled downloads take as long as they take, so the
+    /// timeout then applies to each read of the response only.
+    ///
     /// The default is 5min.
     pub fn timeout(&mut self, timeout: std::time::Duration) -> &mut Self {
         self.timeout = timeout;
@@ -195,6 +310,37 @@ impl Builder {
         self
     }
 
+    /// Set the number of requests that may run in parallel per host.
+    ///
+    /// This applies to all downloads of the `Downloader`, on top of the
+    /// number of parallel requests.
+    ///
+    /// The default is to not limit requests per host.
+    pub fn connections_per_host(&mut self, count: u16) -> &mut Self {
+        self.connections_per_host = Some(count);
+        self
+    }
+
+    /// Set the minimum delay between starting requests to the same host.
+    ///
+    /// The default is to not wait between requests.
+    pub fn host_delay(&mut self, delay: std::time::Duration) -> &mut Self {
+        self.host_delay = delay;
+        self
+    }
+
+    /// Set the maximum rate to receive data at in bytes per second.
+    ///
+    /// This applies to all downloads of the `Downloader` together. The
+    /// timeout is applied to each read of a response then, not to the whole
+    /// request.
+    ///
+    /// The default is to not limit the bandwidth.
+    pub fn max_bandwidth(&mut self, bytes_per_second: u64) -> &mut Self {
+        self.max_bandwidth = Some(bytes_per_second);
+        self
+    }
+
     /// Set the number of retries.
     ///
     /// The default is 3.
@@ -203,6 +349,19 @@ impl Builder {
         self
     }
 
+    /// Set the policy on how long to wait between retries.
+    ///
+    /// A delay requested by the server via a `Retry-After` header is honored.
+    /// If it exceeds the maximum delay of the policy, the mirror is dropped and
+    /// the download continues with the other mirrors, if there are any.
+    ///
+    /// The default is an exponential backoff with jitter, starting at 0.5s
+    /// and waiting for 30s at most.
+    pub fn retry_policy(&mut self, policy: crate::RetryPolicy) -> &mut Self {
+        self.retry_policy = policy;
+        self
+    }
+
     /// Set the folder to download into.
     ///
     /// The default is unset and a value is required.
@@ -211,6 +370,46 @@ impl Builder {
         self
     }
 
+    /// Set the strategy used to pick the mirror to download from.
+    ///
+    /// The default is to pick a mirror at random.
+    pub fn mirror_selector(&mut self, selector: crate::mirror::Selector) -> &mut Self {
+        self.mirror_selector = selector;
+        self
+    }
+
+    /// Set what to do when the file of a `Download` exists already.
+    ///
+    /// The default is to fail the download.
+    pub fn existing_file(&mut self, policy: ExistingFilePolicy) -> &mut Self {
+        self.existing_file = policy;
+        self
+    }
+
+    /// Set the folder to cache downloaded files in.
+    ///
+    /// Files are taken from the cache instead of downloading them when the
+    /// cached copy passes verification. Only verified downloads are added to
+    /// the cache, so this requires `Download`s to set up verification.
+    /// The folder can be shared between several `Downloader`s.
+    ///
+    /// The default is to not cache files.
+    pub fn cache_folder(&mut self, folder: &std::path::Path) -> &mut Self {
+        self.cache_folder = Some(folder.to_path_buf());
+        self
+    }
+
+    /// Set the size in bytes the cache may grow to.
+    ///
+    /// The least recently used files are removed from the cache when it
+    /// grows beyond this size.
+    ///
+    /// The default is to never remove files from the cache.
+    pub fn cache_size(&mut self, size: u64) -> &mut Self {
+        self.cache_size = Some(size);
+        self
+    }
+
     /// Construct a new `reqwest::Client` configured with settings from the `Builder`
     ///
     /// # Errors
@@ -219,7 +418,7 @@ impl Builder {
         reqwest::Client::builder()
             .user_agent(self.user_agent.clone())
             .connect_timeout(self.connect_timeout)
-            .timeout(self.timeout)
+            .read_timeout(self.timeout)
             .build()
             .map_err(|e| Error::Setup(format!("Failed to set up backend: {e}")))
     }
@@ -242,11 +441,35 @@ impl Builder {
             )));
         }
 
+        let cache = match &self.cache_folder {
+            Some(folder) => {
+                std::fs::create_dir_all(folder).map_err(|e| {
+                    Error::Setup(format!(
+                        "Failed to create cache folder \"{}\": {e}",
+                        folder.to_string_lossy()
+                    ))
+                })?;
+                Some(crate::cache::Cache::new(folder.clone(), self.cache_size))
+            }
+            None => None,
+        };
+
         Ok(Downloader {
             client,
+            timeout: None,
             parallel_requests: self.parallel_requests,
             retries: self.retries,
+            retry_policy: self.retry_policy,
             download_folder: download_folder.clone(),
+            mirror_selector: self.mirror_selector.clone(),
+            existing_file: self.existing_file,
+            cache,
+            host_stats: crate::mirror::HostStats::default(),
+            host_limits: crate::throttle::HostLimits::new(
+                self.connections_per_host.map(usize::from),
+                self.host_delay,
+            ),
+            bandwidth: self.max_bandwidth.map(crate::throttle::Bandwidth::new),
         })
     }
 
@@ -256,7 +479,9 @@ impl Builder {
     /// * `Error::Setup`, when setup fails
     pub fn build(&mut self) -> crate::Result<Downloader> {
         let client = self.build_client()?;
-        self.build_with_client(client)
+        let mut downloader = self.build_with_client(client)?;
+        downloader.timeout = Some(self.timeout);
+        Ok(downloader)
     }
 }
 
@@ -277,8 +502,16 @@ impl Default for Builder {
             connect_timeout: std::time::Duration::from_secs(30),
             timeout: std::time::Duration::from_secs(300),
             parallel_requests: 32,
+            connections_per_host: None,
+            host_delay: std::time::Duration::ZERO,
+            max_bandwidth: None,
             retries: 3,
+            retry_policy: crate::RetryPolicy::default(),
             download_folder,
+            mirror_selector: crate::mirror::Random::create(),
+            existing_file: ExistingFilePolicy::default(),
+            cache_folder: None,
+            cache_size: None,
         }
     }
 }
@@ -25,15 +25,26 @@
 #![allow(clippy::non_ascii_literal)]
 
 pub mod backend;
+mod cache;
+#[cfg(feature = "verify")]
+pub mod checksum;
 pub mod download;
 pub mod downloader;
+pub mod mirror;
 pub mod progress;
+pub mod retry;
+mod throttle;
+mod validators;
 pub mod verify;
 
-pub use crate::download::Download;
+pub use crate::download::{Download, ExistingFilePolicy};
 pub use crate::downloader::Downloader;
+pub use crate::mirror::MirrorSelector;
 pub use crate::progress::Progress;
-pub use crate::verify::{SimpleProgress, Verification, Verify};
+pub use crate::retry::RetryPolicy;
+
+pub use crate::verify::{AsyncVerify, SimpleProgress, Verification, Verify};
+use std::convert::TryFrom;
 
 // ----------------------------------------------------------------------
 // - Error:
@@ -57,44 +68,253 @@ pub enum Error {
     /// Download file verification failed.
     #[error("Verification failed for {0}")]
     Verification(DownloadSummary),
+    /// The servers sent data of a different size or type than expected.
+    #[error("Unexpected content for {0}")]
+    UnexpectedContent(DownloadSummary),
+    /// The file to download into exists already.
+    #[error("File exists already for {0}")]
+    FileExists(DownloadSummary),
 }
 
 /// `Result` type for the `gng_shared` library
 pub type Result<T> = std::result::Result<T, Error>;
 
+// ----------------------------------------------------------------------
+// - AttemptOutcome:
+// ----------------------------------------------------------------------
+
+/// The outcome of one attempt to fetch a URL
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AttemptOutcome {
+    /// The server answered with this HTTP status code.
+    Status(u16),
+    /// Connecting to the server failed.
+    Connect(String),
+    /// The server did not answer in time.
+    Timeout,
+    /// Setting up a secure connection to the server failed.
+    Tls(String),
+    /// Reading the response body failed.
+    Body(String),
+    /// The request could not be sent.
+    Request(String),
+    /// The server sent less data than it announced.
+    Truncated {
+        /// The number of bytes announced
+        expected: u64,
+        /// The number of bytes received
+        received: u64,
+    },
+    /// Writing the received data failed.
+    Write(String),
+    /// The data is larger than allowed for the `Download`.
+    TooLarge {
+        /// The maximum number of bytes allowed
+        limit: u64,
+    },
+    /// The data does not have the expected size.
+    UnexpectedSize {
+        /// The expected size in bytes
+        expected: u64,
+        /// The size reported or received
+        actual: u64,
+    },
+    /// The data does not have one of the expected content types.
+    UnexpectedContentType(Option<String>),
+}
+
+impl AttemptOutcome {
+    /// Whether the attempt succeeded.
+    #[must_use]
+    pub const fn is_success(&self) -> bool {
+        matches!(self, Self::Status(200..=299))
+    }
+
+    /// Whether trying again might succeed.
+    ///
+    /// Client errors like 404 (Not Found), 403 (Forbidden) or 410 (Gone) as
+    /// well as TLS errors, broken requests and failures to write the data
+    /// are permanent, most other
+    /// errors are considered transient.
+    #[must_use]
+    pub const fn is_retryable(&self) -> bool {
+        match self {
+            Self::Status(status) => matches!(*status, 408 | 416 | 425 | 429 | 500..=599),
+            Self::Connect(_) | Self::Timeout | Self::Body(_) | Self::Truncated { .. } => true,
+            Self::Tls(_)
+            | Self::Request(_)
+            | Self::Write(_)
+            | Self::TooLarge { .. }
+            | Self::UnexpectedSize { .. }
+            | Self::UnexpectedContentType(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for AttemptOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "status {status}"),
+            Self::Connect(message) => write!(f, "connection failed: {message}"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Tls(message) => write!(f, "TLS error: {message}"),
+            Self::Body(message) => write!(f, "reading data failed: {message}"),
+            Self::Request(message) => write!(f, "invalid request: {message}"),
+            Self::Truncated { expected, received } => {
+                write!(f, "truncated: received {received} of {expected} bytes")
+            }
+            Self::Write(message) => write!(f, "writing data failed: {message}"),
+            Self::TooLarge { limit } => write!(f, "too large: more than {limit} bytes"),
+            Self::UnexpectedSize { expected, actual } => {
+                write!(f, "unexpected size: {actual} bytes instead of {expected}")
+            }
+            Self::UnexpectedContentType(Some(content_type)) => {
+                write!(f, "unexpected content type: {content_type}")
+            }
+            Self::UnexpectedContentType(None) => write!(f, "no content type"),
+        }
+    }
+}
+
+// ----------------------------------------------------------------------
+// - Attempt:
+// ----------------------------------------------------------------------
+
+/// A record of one attempt to fetch (part of) a file from a URL
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Attempt {
+    /// The URL that was requested.
+    pub url: String,
+    /// The URL that answered after following redirects, if there was an answer.
+    pub final_url: Option<String>,
+    /// How the attempt ended.
+    pub outcome: AttemptOutcome,
+    /// The number of bytes received.
+    pub bytes: u64,
+    /// The time from sending the request to receiving the last byte.
+    pub duration: std::time::Duration,
+    /// Whether the attempt continued data received earlier.
+    pub resumed: bool,
+    /// The delay the server asked for before trying again
+    pub retry_after: Option<std::time::Duration>,
+}
+
+impl Attempt {
+    /// The HTTP status code the server answered with, if any.
+    #[must_use]
+    pub const fn status(&self) -> Option<u16> {
+        match self.outcome {
+            AttemptOutcome::Status(status) => Some(status),
+            _ => None,
+        }
+    }
+
+    /// The average throughput in bytes per second
+    #[must_use]
+    pub fn throughput(&self) -> u64 {
+        let millis = self.duration.as_millis().max(1);
+        u64::try_from(u128::from(self.bytes) * 1000 / millis).unwrap_or(u64::MAX)
+    }
+}
+
+// ----------------------------------------------------------------------
+// - DropReason:
+// ----------------------------------------------------------------------
+
+/// The reason a mirror was excluded from a `Download`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DropReason {
+    /// The server answered with an error status code.
+    Status(u16),
+    /// Connecting to the server or receiving data from it failed.
+    Connection(String),
+    /// The server did not answer in time.
+    Timeout,
+    /// Setting up a secure connection to the server failed.
+    Tls(String),
+    /// The server was much slower than the other mirrors.
+    TooSlow,
+    /// The server sent data of a different size or type than expected.
+    UnexpectedContent(String),
+    /// The data received from the server failed verification.
+    VerificationFailed(crate::verify::Failure),
+}
+
+impl std::fmt::Display for DropReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "status {status}"),
+            Self::Connection(message) => write!(f, "connection failed: {message}"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Tls(message) => write!(f, "TLS error: {message}"),
+            Self::TooSlow => write!(f, "too slow"),
+            Self::UnexpectedContent(message) => write!(f, "{message}"),
+            Self::VerificationFailed(failure) => write!(f, "verification failed: {failure}"),
+        }
+    }
+}
+
 // ----------------------------------------------------------------------
 // - DownloadSummary:
 // ----------------------------------------------------------------------
 
+/// How a `Download` was completed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Completion {
+    /// The data was downloaded.
+    Downloaded,
+    /// The file existed already and was kept.
+    Skipped,
+    /// The file existed already and the server reported it as unchanged.
+    UpToDate,
+    /// The file was taken from the cache.
+    Cached,
+}
+
+impl std::fmt::Display for Completion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Downloaded => write!(f, "downloaded"),
+            Self::Skipped => write!(f, "skipped"),
+            Self::UpToDate => write!(f, "up to date"),
+            Self::Cached => write!(f, "cached"),
+        }
+    }
+}
+
 /// The result of a `Download`
 pub struct DownloadSummary {
-    /// A list of attempted downloads with URL and status code.
-    pub status: Vec<(String, u16)>,
+    /// A list of attempted downloads.
+    pub status: Vec<Attempt>,
+    /// The mirrors that were excluded from the download and why.
+    pub dropped_mirrors: Vec<(String, DropReason)>,
     /// The path this URL has been downloaded to.
     pub file_name: std::path::PathBuf,
+    /// How the download was completed
+    pub completion: Completion,
     /// File verification status
     pub verified: Verification,
+    /// The downloaded data of a `Download` with a `Target::Memory`.
+    pub data: Option<Vec<u8>>,
 }
 
 fn to_fmt(f: &mut std::fmt::Formatter<'_>, summary: &DownloadSummary) -> std::fmt::Result {
-    writeln!(
-        f,
-        "{}: (verification: {}):",
-        summary.file_name.to_string_lossy(),
-        match summary.verified {
-            Verification::NotVerified => "unverified",
-            Verification::Failed => "FAILED",
-            Verification::Ok => "Ok",
-        },
-    )?;
-    for i in 0..summary.status.len() {
-        writeln!(
-            f,
-            "  {}: {} with status {}",
-            i + 1,
-            summary.status[i].0,
-            summary.status[i].1
-        )?;
+    write!(f, "{}: (", summary.file_name.to_string_lossy())?;
+    if summary.completion != Completion::Downloaded {
+        write!(f, "{}, ", summary.completion)?;
+    }
+    write!(f, "verification: ")?;
+    match &summary.verified {
+        Verification::NotVerified => write!(f, "unverified")?,
+        Verification::Failed(failure) => write!(f, "FAILED: {failure}")?,
+        Verification::Ok => write!(f, "Ok")?,
+    }
+    writeln!(f, "):")?;
+    for (i, attempt) in summary.status.iter().enumerate() {
+        writeln!(f, "  {}: {} with {}", i + 1, attempt.url, attempt.outcome)?;
+    }
+    for (url, reason) in &summary.dropped_mirrors {
+        writeln!(f, "  dropped {url}: {reason}")?;
     }
     Ok(())
 }
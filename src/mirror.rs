@@ -0,0 +1,425 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! Strategies to pick the mirror to download from
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use std::convert::TryFrom;
+
+// ----------------------------------------------------------------------
+// - Types:
+// ----------------------------------------------------------------------
+
+/// A `MirrorSelector` to use for a `Download`
+pub type Selector = std::sync::Arc<dyn MirrorSelector>;
+
+/// The host (and port, if any) of `url`
+#[must_use]
+pub fn host_of(url: &str) -> String {
+    reqwest::Url::parse(url).map_or_else(
+        |_| String::new(),
+        |u| {
+            let host = u.host_str().unwrap_or("");
+            u.port()
+                .map_or_else(|| host.to_owned(), |p| format!("{host}:{p}"))
+        },
+    )
+}
+
+// ----------------------------------------------------------------------
+// - HostStats:
+// ----------------------------------------------------------------------
+
+/// What is known about the performance of one host
+#[derive(Clone, Debug, Default)]
+pub struct HostRecord {
+    /// The number of requests that succeeded
+    pub successes: u32,
+    /// The number of requests that failed
+    pub failures: u32,
+    /// The time it took the host to answer the last request
+    pub latency: Option<std::time::Duration>,
+    /// The throughput of the last successful request in bytes per second
+    pub throughput: Option<u64>,
+}
+
+/// Performance data on hosts
+///
+/// This is shared by all downloads of a `Downloader`.
+#[derive(Clone, Default)]
+pub struct HostStats {
+    hosts: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, HostRecord>>>,
+}
+
+impl HostStats {
+    /// Get the record of the host serving `url`
+    #[must_use]
+    pub fn get(&self, url: &str) -> Option<HostRecord> {
+        self.hosts
+            .lock()
+            .ok()
+            .and_then(|hosts| hosts.get(&host_of(url)).cloned())
+    }
+
+    fn update(&self, url: &str, func: impl FnOnce(&mut HostRecord)) {
+        if let Ok(mut hosts) = self.hosts.lock() {
+            func(hosts.entry(host_of(url)).or_default());
+        }
+    }
+
+    pub(crate) fn record_latency(&self, url: &str, latency: std::time::Duration) {
+        self.update(url, |r| r.latency = Some(latency));
+    }
+
+    pub(crate) fn record_success(&self, url: &str, bytes: u64, duration: std::time::Duration) {
+        let millis = duration.as_millis().max(1);
+        let throughput = u64::try_from(u128::from(bytes) * 1000 / millis).unwrap_or(u64::MAX);
+        self.update(url, |r| {
+            r.successes += 1;
+            r.throughput = Some(throughput);
+        });
+    }
+
+    pub(crate) fn record_failure(&self, url: &str) {
+        self.update(url, |r| r.failures += 1);
+    }
+}
+
+// ----------------------------------------------------------------------
+// - MirrorHealth:
+// ----------------------------------------------------------------------
+
+/// The mirrors of one download that are still usable
+#[derive(Default)]
+pub(crate) struct MirrorHealth {
+    healthy: Vec<String>,
+    dropped: Vec<(String, crate::DropReason)>,
+}
+
+impl MirrorHealth {
+    pub(crate) fn new(urls: &[String]) -> Self {
+        Self {
+            healthy: urls.to_vec(),
+            dropped: Vec::new(),
+        }
+    }
+
+    /// The mirrors that were not dropped yet
+    pub(crate) fn healthy(&self) -> &[String] {
+        &self.healthy
+    }
+
+    /// Whether `url` was not dropped yet
+    pub(crate) fn is_healthy(&self, url: &str) -> bool {
+        self.healthy.iter().any(|u| u == url)
+    }
+
+    /// Exclude `url` for the remainder of the download
+    pub(crate) fn drop_mirror(&mut self, url: &str, reason: crate::DropReason) {
+        if let Some(index) = self.healthy.iter().position(|u| u == url) {
+            self.healthy.remove(index);
+            self.dropped.push((url.to_owned(), reason));
+        }
+    }
+
+    pub(crate) fn into_dropped(self) -> Vec<(String, crate::DropReason)> {
+        self.dropped
+    }
+}
+
+// ----------------------------------------------------------------------
+// - Traits:
+// ----------------------------------------------------------------------
+
+/// An interface to pick the mirror to download from.
+pub trait MirrorSelector: Send + Sync {
+    /// Pick the URL to use for the `attempt`-th try (starting at 1) to
+    /// fetch a file from. `urls` is never empty and holds the mirrors
+    /// that were not dropped yet, in the order they were given in.
+    ///
+    /// Return the index into `urls`.
+    fn select(&self, urls: &[String], attempt: u16, stats: &HostStats) -> usize;
+
+    /// Whether the hosts should be probed for their latency before
+    /// `select` gets called.
+    fn needs_latency(&self) -> bool {
+        false
+    }
+}
+
+// ----------------------------------------------------------------------
+// - Random:
+// ----------------------------------------------------------------------
+
+/// Pick a mirror at random
+#[derive(Default)]
+pub struct Random {}
+
+impl MirrorSelector for Random {
+    fn select(&self, urls: &[String], _: u16, _: &HostStats) -> usize {
+        rand::thread_rng().gen_range(0..urls.len())
+    }
+}
+
+impl Random {
+    /// Create a `Random` `MirrorSelector`.
+    #[must_use]
+    pub fn create() -> Selector {
+        std::sync::Arc::new(Self {})
+    }
+}
+
+// ----------------------------------------------------------------------
+// - Ordered:
+// ----------------------------------------------------------------------
+
+/// Try the mirrors in the order they were given in
+///
+/// A mirror is used till it gets dropped, then the next one is tried.
+#[derive(Default)]
+pub struct Ordered {}
+
+impl MirrorSelector for Ordered {
+    fn select(&self, _: &[String], _: u16, _: &HostStats) -> usize {
+        0
+    }
+}
+
+impl Ordered {
+    /// Create an `Ordered` `MirrorSelector`.
+    #[must_use]
+    pub fn create() -> Selector {
+        std::sync::Arc::new(Self {})
+    }
+}
+
+// ----------------------------------------------------------------------
+// - Weighted:
+// ----------------------------------------------------------------------
+
+/// Pick mirrors at random, preferring hosts with a higher weight
+pub struct Weighted {
+    weights: std::collections::HashMap<String, u32>,
+    default_weight: u32,
+}
+
+impl MirrorSelector for Weighted {
+    fn select(&self, urls: &[String], _: u16, _: &HostStats) -> usize {
+        let indices: Vec<usize> = (0..urls.len()).collect();
+        indices
+            .choose_weighted(&mut rand::thread_rng(), |i| {
+                self.weights
+                    .get(&host_of(&urls[*i]))
+                    .copied()
+                    .unwrap_or(self.default_weight)
+            })
+            .map_or(0, |i| *i)
+    }
+}
+
+impl Weighted {
+    /// Create a `Weighted` `MirrorSelector` from a list of host names
+    /// and their weights.
+    ///
+    /// Hosts that are not listed get a weight of 1.
+    #[must_use]
+    pub fn create(weights: &[(&str, u32)]) -> Selector {
+        std::sync::Arc::new(Self {
+            weights: weights
+                .iter()
+                .map(|(host, weight)| ((*host).to_owned(), *weight))
+                .collect(),
+            default_weight: 1,
+        })
+    }
+}
+
+// ----------------------------------------------------------------------
+// - RoundRobin:
+// ----------------------------------------------------------------------
+
+/// Cycle through the mirrors
+///
+/// The position is shared between all downloads using the same
+/// `RoundRobin` object.
+#[derive(Default)]
+pub struct RoundRobin {
+    next: std::sync::atomic::AtomicUsize,
+}
+
+impl MirrorSelector for RoundRobin {
+    fn select(&self, urls: &[String], _: u16, _: &HostStats) -> usize {
+        self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % urls.len()
+    }
+}
+
+impl RoundRobin {
+    /// Create a `RoundRobin` `MirrorSelector`.
+    #[must_use]
+    pub fn create() -> Selector {
+        std::sync::Arc::new(Self::default())
+    }
+}
+
+// ----------------------------------------------------------------------
+// - LatencyRanked:
+// ----------------------------------------------------------------------
+
+/// Prefer the mirror that answers fastest
+///
+/// Hosts are probed with a `HEAD` request if their latency is not known yet.
+/// Hosts that failed more requests than they served are ranked after the
+/// others, so a host that recovered from a failure is preferred again.
+#[derive(Default)]
+pub struct LatencyRanked {}
+
+impl MirrorSelector for LatencyRanked {
+    fn select(&self, urls: &[String], _: u16, stats: &HostStats) -> usize {
+        let mut ranked: Vec<(usize, (u32, std::time::Duration))> = urls
+            .iter()
+            .enumerate()
+            .map(|(i, u)| {
+                let record = stats.get(u).unwrap_or_default();
+                (
+                    i,
+                    (
+                        record.failures.saturating_sub(record.successes),
+                        record.latency.unwrap_or(std::time::Duration::MAX),
+                    ),
+                )
+            })
+            .collect();
+        ranked.sort_by_key(|(_, rank)| *rank);
+        ranked[0].0
+    }
+
+    fn needs_latency(&self) -> bool {
+        true
+    }
+}
+
+impl LatencyRanked {
+    /// Create a `LatencyRanked` `MirrorSelector`.
+    #[must_use]
+    pub fn create() -> Selector {
+        std::sync::Arc::new(Self {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn urls(hosts: &[&str]) -> Vec<String> {
+        hosts.iter().map(|h| format!("https://{h}/file")).collect()
+    }
+
+    #[test]
+    fn host_with_port() {
+        assert_eq!(host_of("https://a.example/file"), "a.example");
+        assert_eq!(host_of("http://a.example:8080/file"), "a.example:8080");
+        assert_eq!(host_of("not a url"), "");
+    }
+
+    #[test]
+    fn drop_mirror() {
+        let urls = urls(&["a", "b", "c"]);
+        let mut health = MirrorHealth::new(&urls);
+        assert!(health.is_healthy(&urls[1]));
+
+        health.drop_mirror(&urls[1], crate::DropReason::Timeout);
+        health.drop_mirror(&urls[1], crate::DropReason::TooSlow);
+        health.drop_mirror("https://unknown/file", crate::DropReason::TooSlow);
+        assert!(!health.is_healthy(&urls[1]));
+        assert_eq!(health.healthy(), &[urls[0].clone(), urls[2].clone()]);
+
+        health.drop_mirror(&urls[0], crate::DropReason::Status(404));
+        assert_eq!(
+            health.into_dropped(),
+            vec![
+                (urls[1].clone(), crate::DropReason::Timeout),
+                (urls[0].clone(), crate::DropReason::Status(404)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ordered_moves_on_when_dropped() {
+        let urls = urls(&["a", "b", "c"]);
+        let stats = HostStats::default();
+        let mut health = MirrorHealth::new(&urls);
+        for attempt in 1..4 {
+            assert_eq!(Ordered {}.select(health.healthy(), attempt, &stats), 0);
+        }
+
+        health.drop_mirror(&urls[0], crate::DropReason::Timeout);
+        let index = Ordered {}.select(health.healthy(), 4, &stats);
+        assert_eq!(health.healthy()[index], urls[1]);
+    }
+
+    #[test]
+    fn random_stays_in_range() {
+        let urls = urls(&["a", "b", "c"]);
+        for _ in 0..100 {
+            assert!(Random {}.select(&urls, 1, &HostStats::default()) < urls.len());
+        }
+    }
+
+    #[test]
+    fn round_robin_cycles() {
+        let selector = RoundRobin::default();
+        let stats = HostStats::default();
+        let three = urls(&["a", "b", "c"]);
+        let picks: Vec<_> = (1..=4)
+            .map(|a| selector.select(&three, a, &stats))
+            .collect();
+        assert_eq!(picks, vec![0, 1, 2, 0]);
+
+        // Fewer mirrors after dropping some:
+        assert_eq!(selector.select(&three[..1], 5, &stats), 0);
+    }
+
+    #[test]
+    fn weighted_skips_zero_weight() {
+        let selector = Weighted::create(&[("a", 0), ("b", 5)]);
+        let stats = HostStats::default();
+        let urls = urls(&["a", "b", "c"]);
+        for _ in 0..100 {
+            assert_ne!(selector.select(&urls, 1, &stats), 0);
+        }
+        // Nothing to choose from at all:
+        assert_eq!(selector.select(&urls[..1], 1, &stats), 0);
+    }
+
+    #[test]
+    fn latency_ranked_prefers_fast_hosts() {
+        let urls = urls(&["a", "b", "c"]);
+        let stats = HostStats::default();
+        stats.record_latency(&urls[0], Duration::from_millis(300));
+        stats.record_latency(&urls[1], Duration::from_millis(100));
+        assert_eq!(LatencyRanked {}.select(&urls, 1, &stats), 1);
+
+        // Unknown latency comes last:
+        assert_eq!(LatencyRanked {}.select(&urls[1..], 1, &stats), 0);
+    }
+
+    #[test]
+    fn latency_ranked_failures() {
+        let urls = urls(&["fast", "slow"]);
+        let stats = HostStats::default();
+        stats.record_latency(&urls[0], Duration::from_millis(10));
+        stats.record_latency(&urls[1], Duration::from_millis(500));
+
+        // A failing host goes last:
+        stats.record_failure(&urls[0]);
+        assert_eq!(LatencyRanked {}.select(&urls, 1, &stats), 1);
+
+        // ... until it serves requests again:
+        stats.record_success(&urls[0], 1000, Duration::from_millis(10));
+        assert_eq!(LatencyRanked {}.select(&urls, 2, &stats), 0);
+    }
+}
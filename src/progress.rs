@@ -127,10 +127,10 @@ mod tui {
                             "▪▪▪▪▪",
                         ])
                         .template("{spinner:.blue} {msg}")
-                        .unwrap()
+                        .unwrap(),
                 );
                 lock.set_message(String::from(message));
-            };
+            }
         }
 
         fn progress(&self, current: u64) {
@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! Policies on how long to wait between attempts to download a file
+
+use rand::Rng;
+
+use std::convert::TryFrom;
+
+// ----------------------------------------------------------------------
+// - Backoff:
+// ----------------------------------------------------------------------
+
+/// How the delay between attempts develops
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backoff {
+    /// Retry right away.
+    Immediate,
+    /// Always wait for the same time.
+    Fixed(std::time::Duration),
+    /// Start with the given delay and double it after each failed attempt.
+    Exponential(std::time::Duration),
+    /// Like `Exponential`, but wait for a random time between zero and the
+    /// computed delay. This spreads out retries of many clients.
+    ExponentialWithJitter(std::time::Duration),
+}
+
+// ----------------------------------------------------------------------
+// - RetryPolicy:
+// ----------------------------------------------------------------------
+
+/// A policy for how long to wait between attempts to download a file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// How the delay develops
+    pub backoff: Backoff,
+    /// The maximum time to wait between attempts
+    pub max_delay: std::time::Duration,
+}
+
+fn exponential(base: std::time::Duration, failures: u16) -> std::time::Duration {
+    let factor = 1_u32
+        .checked_shl(u32::from(failures.saturating_sub(1)))
+        .unwrap_or(u32::MAX);
+    base.saturating_mul(factor)
+}
+
+impl RetryPolicy {
+    /// Create a new `RetryPolicy`
+    #[must_use]
+    pub const fn new(backoff: Backoff, max_delay: std::time::Duration) -> Self {
+        Self { backoff, max_delay }
+    }
+
+    /// The time to wait after `failures` failed attempts, `None` if there
+    /// should be no further attempt.
+    ///
+    /// A `retry_after` delay requested by the server takes precedence over
+    /// the `backoff`. Delays computed from the `backoff` are capped at
+    /// `max_delay`. A server asking to wait for longer than `max_delay` gets
+    /// no further attempt rather than one that comes too early.
+    #[must_use]
+    pub fn delay(
+        &self,
+        failures: u16,
+        retry_after: Option<std::time::Duration>,
+    ) -> Option<std::time::Duration> {
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+        let delay = match self.backoff {
+            Backoff::Immediate => std::time::Duration::ZERO,
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential(base) => exponential(base, failures),
+            Backoff::ExponentialWithJitter(base) => {
+                let max = exponential(base, failures).min(self.max_delay);
+                let millis = u64::try_from(max.as_millis()).unwrap_or(u64::MAX);
+                std::time::Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+            }
+        };
+        Some(delay.min(self.max_delay))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            backoff: Backoff::ExponentialWithJitter(std::time::Duration::from_millis(500)),
+            max_delay: std::time::Duration::from_secs(30),
+        }
+    }
+}
+
+/// Parse the value of a `Retry-After` header
+///
+/// This can either be a number of seconds or a HTTP date.
+pub(crate) fn parse_retry_after(value: &str) -> Option<std::time::Duration> {
+    let value = value.trim();
+    value.parse::<u64>().map_or_else(
+        |_| {
+            httpdate::parse_http_date(value).ok().map(|at| {
+                at.duration_since(std::time::SystemTime::now())
+                    .unwrap_or_default()
+            })
+        },
+        |seconds| Some(std::time::Duration::from_secs(seconds)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("-1"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let at = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(100));
+        let delay = parse_retry_after(&at).unwrap();
+        assert!(delay > Duration::from_secs(98) && delay <= Duration::from_secs(100));
+    }
+
+    #[test]
+    fn retry_after_in_the_past() {
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn exponential_doubles() {
+        let base = Duration::from_millis(500);
+        assert_eq!(exponential(base, 0), base);
+        assert_eq!(exponential(base, 1), base);
+        assert_eq!(exponential(base, 2), base * 2);
+        assert_eq!(exponential(base, 4), base * 8);
+    }
+
+    #[test]
+    fn exponential_overflow() {
+        let base = Duration::from_secs(1);
+        assert_eq!(exponential(base, 33), base * u32::MAX);
+        assert_eq!(exponential(base, u16::MAX), base * u32::MAX);
+        assert_eq!(exponential(Duration::MAX, 2), Duration::MAX);
+
+        let policy = RetryPolicy::new(Backoff::Exponential(base), Duration::from_secs(30));
+        assert_eq!(policy.delay(u16::MAX, None), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        let max_delay = Duration::from_secs(3);
+        let policy = RetryPolicy::new(
+            Backoff::ExponentialWithJitter(Duration::from_millis(500)),
+            max_delay,
+        );
+        for failures in 0..10 {
+            let bound = exponential(Duration::from_millis(500), failures).min(max_delay);
+            for _ in 0..100 {
+                assert!(policy.delay(failures, None).unwrap() <= bound);
+            }
+        }
+        assert_eq!(
+            RetryPolicy::new(Backoff::ExponentialWithJitter(Duration::ZERO), max_delay)
+                .delay(5, None),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let max_delay = Duration::from_secs(30);
+        let policy = RetryPolicy::new(Backoff::Fixed(Duration::from_secs(60)), max_delay);
+        assert_eq!(policy.delay(1, None), Some(max_delay));
+
+        let policy = RetryPolicy::new(Backoff::Immediate, max_delay);
+        assert_eq!(policy.delay(1, None), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_takes_precedence() {
+        let policy = RetryPolicy::new(Backoff::Immediate, Duration::from_secs(30));
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(30))),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(31))), None);
+    }
+}
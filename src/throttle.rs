@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! Limits on the requests and bandwidth used by a `Downloader`
+
+use std::convert::TryFrom;
+
+// ----------------------------------------------------------------------
+// - HostLimits:
+// ----------------------------------------------------------------------
+
+/// The state of one host
+struct Host {
+    connections: Option<std::sync::Arc<tokio::sync::Semaphore>>,
+    next_request: tokio::sync::Mutex<std::time::Instant>,
+}
+
+/// Allows for one request to a host, keep it around till the request is done
+pub struct Permit {
+    _connection: Option<tokio::sync::OwnedSemaphorePermit>,
+}
+
+/// Limits on the requests sent to each host
+///
+/// This is shared by all downloads of a `Downloader`.
+#[derive(Clone, Default)]
+pub struct HostLimits {
+    connections: Option<usize>,
+    delay: std::time::Duration,
+    hosts:
+        std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<Host>>>>,
+}
+
+impl HostLimits {
+    /// Allow for at most `connections` requests to run at the same time per
+    /// host and wait for `delay` between starting requests to the same host.
+    pub fn new(connections: Option<usize>, delay: std::time::Duration) -> Self {
+        Self {
+            connections,
+            delay,
+            hosts: std::sync::Arc::default(),
+        }
+    }
+
+    fn host(&self, url: &str) -> std::sync::Arc<Host> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .entry(crate::mirror::host_of(url))
+            .or_insert_with(|| {
+                std::sync::Arc::new(Host {
+                    connections: self
+                        .connections
+                        .map(|c| std::sync::Arc::new(tokio::sync::Semaphore::new(c.max(1)))),
+                    next_request: tokio::sync::Mutex::new(std::time::Instant::now()),
+                })
+            })
+            .clone()
+    }
+
+    /// Wait till a request to the host serving `url` may be sent
+    pub async fn acquire(&self, url: &str) -> Permit {
+        if self.connections.is_none() && self.delay.is_zero() {
+            return Permit { _connection: None };
+        }
+
+        let host = self.host(url);
+        let connection = match &host.connections {
+            Some(connections) => connections.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        if !self.delay.is_zero() {
+            let mut next_request = host.next_request.lock().await;
+            let now = std::time::Instant::now();
+            if *next_request > now {
+                tokio::time::sleep(*next_request - now).await;
+            }
+            *next_request = std::time::Instant::now() + self.delay;
+        }
+        Permit {
+            _connection: connection,
+        }
+    }
+}
+
+// ----------------------------------------------------------------------
+// - Bandwidth:
+// ----------------------------------------------------------------------
+
+/// The amount of data that may be received at once after a pause
+const BURST: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// A token bucket limiting the rate data is received at
+///
+/// Clones share the same bucket.
+#[derive(Clone, Debug)]
+pub struct Bandwidth {
+    bytes_per_second: u64,
+    /// The time at which all data received so far is paid for
+    paid_until: std::sync::Arc<std::sync::Mutex<tokio::time::Instant>>,
+}
+
+impl Bandwidth {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            paid_until: std::sync::Arc::new(std::sync::Mutex::new(tokio::time::Instant::now())),
+        }
+    }
+
+    /// Wait till receiving `bytes` more bytes keeps within the limit
+    pub async fn consume(&self, bytes: u64) {
+        let nanos = u128::from(bytes) * 1_000_000_000 / u128::from(self.bytes_per_second);
+        let cost = std::time::Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+
+        let now = tokio::time::Instant::now();
+        let wait = {
+            let mut paid_until = self.paid_until.lock().unwrap();
+            let earliest = now.checked_sub(BURST).unwrap_or(now);
+            *paid_until = (*paid_until).max(earliest) + cost;
+            paid_until.saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    async fn elapsed(bandwidth: &Bandwidth, bytes: u64) -> Duration {
+        let start = tokio::time::Instant::now();
+        bandwidth.consume(bytes).await;
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bandwidth_limits_rate() {
+        let bandwidth = Bandwidth::new(1000);
+        assert_eq!(elapsed(&bandwidth, 500).await, Duration::from_millis(500));
+        assert_eq!(elapsed(&bandwidth, 2000).await, Duration::from_secs(2));
+        assert_eq!(elapsed(&bandwidth, 0).await, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bandwidth_allows_burst_after_pause() {
+        let bandwidth = Bandwidth::new(1000);
+        bandwidth.consume(1000).await;
+
+        // Time not used to receive data is saved up to one second's worth:
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(elapsed(&bandwidth, 1000).await, Duration::ZERO);
+        assert_eq!(elapsed(&bandwidth, 500).await, Duration::from_millis(500));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(elapsed(&bandwidth, 500).await, Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bandwidth_is_shared_by_clones() {
+        let bandwidth = Bandwidth::new(1000);
+        let clone = bandwidth.clone();
+        assert_eq!(elapsed(&bandwidth, 1000).await, Duration::from_secs(1));
+        assert_eq!(elapsed(&clone, 1000).await, Duration::from_secs(1));
+
+        // Both wait their turn when receiving at the same time:
+        let start = tokio::time::Instant::now();
+        futures::join!(bandwidth.consume(1000), clone.consume(1000));
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+}
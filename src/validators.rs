@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+// Copyright (C) 2021 Tobias Hunger <tobias.hunger@gmail.com>
+
+//! HTTP validators used to make sure partial data belongs to the same resource
+
+// ----------------------------------------------------------------------
+// - Validators:
+// ----------------------------------------------------------------------
+
+/// The `ETag` and `Last-Modified` values a server reported for a resource.
+///
+/// Completed downloads also keep the size of the file and the URL it was
+/// downloaded from.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_length: Option<u64>,
+    pub url: Option<String>,
+}
+
+fn header_value(response: &reqwest::Response, name: reqwest::header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+impl Validators {
+    /// Extract the validators from a `response`
+    pub fn from_response(response: &reqwest::Response) -> Self {
+        Self {
+            etag: header_value(response, reqwest::header::ETAG),
+            last_modified: header_value(response, reqwest::header::LAST_MODIFIED),
+            ..Self::default()
+        }
+    }
+
+    /// The value to send in an `If-Range` header, if any.
+    ///
+    /// Weak `ETag`s must not be used in `If-Range`, so fall back to
+    /// `Last-Modified` for those.
+    pub fn if_range(&self) -> Option<&str> {
+        match (&self.etag, &self.last_modified) {
+            (Some(etag), _) if !etag.starts_with("W/") => Some(etag),
+            (_, Some(last_modified)) => Some(last_modified),
+            _ => None,
+        }
+    }
+
+    /// Make `request` conditional on the resource having changed
+    pub fn if_changed(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+
+    /// The path of the sidecar file used to persist the validators for `path`
+    pub fn sidecar_path(path: &std::path::Path) -> std::path::PathBuf {
+        Self::with_suffix(path, ".resume")
+    }
+
+    /// The path of the sidecar file used to persist the validators of the
+    /// completed download in `path`
+    pub fn completed_path(path: &std::path::Path) -> std::path::PathBuf {
+        Self::with_suffix(path, ".validators")
+    }
+
+    fn with_suffix(path: &std::path::Path, suffix: &str) -> std::path::PathBuf {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        std::path::PathBuf::from(sidecar)
+    }
+
+    fn read(sidecar: &std::path::Path) -> Self {
+        let mut result = Self::default();
+
+        if let Ok(contents) = std::fs::read_to_string(sidecar) {
+            for line in contents.lines() {
+                if let Some((key, value)) = line.split_once(": ") {
+                    match key {
+                        "ETag" => result.etag = Some(value.to_owned()),
+                        "Last-Modified" => result.last_modified = Some(value.to_owned()),
+                        "Content-Length" => result.content_length = value.parse().ok(),
+                        "URL" => result.url = Some(value.to_owned()),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn write(&self, sidecar: &std::path::Path) -> std::io::Result<()> {
+        let content_length = self.content_length.map(|l| l.to_string());
+        let contents: String = [
+            ("ETag", &self.etag),
+            ("Last-Modified", &self.last_modified),
+            ("Content-Length", &content_length),
+            ("URL", &self.url),
+        ]
+        .iter()
+        .filter_map(|(key, value)| value.as_ref().map(|v| format!("{key}: {v}\n")))
+        .collect();
+        std::fs::write(sidecar, contents)
+    }
+
+    /// Load the validators stored for `path`
+    ///
+    /// Missing or broken sidecar files result in empty `Validators`.
+    pub fn load(path: &std::path::Path) -> Self {
+        Self::read(&Self::sidecar_path(path))
+    }
+
+    /// Store the validators for `path`
+    pub fn store(&self, path: &std::path::Path) -> std::io::Result<()> {
+        self.write(&Self::sidecar_path(path))
+    }
+
+    /// Remove the validators stored for `path`
+    pub fn remove(path: &std::path::Path) {
+        _ = std::fs::remove_file(Self::sidecar_path(path));
+    }
+
+    /// Load the validators stored for the completed download in `path`
+    pub fn load_completed(path: &std::path::Path) -> Self {
+        Self::read(&Self::completed_path(path))
+    }
+
+    /// Store the validators for the completed download in `path`
+    pub fn store_completed(&self, path: &std::path::Path) -> std::io::Result<()> {
+        self.write(&Self::completed_path(path))
+    }
+}
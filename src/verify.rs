@@ -17,28 +17,168 @@ pub type SimpleProgress = dyn Fn(u64) + Sync;
 pub type Verify =
     std::sync::Arc<dyn Fn(std::path::PathBuf, &SimpleProgress) -> Verification + Send + Sync>;
 
+/// A callback used to verify the download without blocking a thread
+///
+/// Use this for verification that needs to wait for I/O, e.g. to look up
+/// the file in a transparency log.
+pub type AsyncVerify = std::sync::Arc<
+    dyn Fn(std::path::PathBuf) -> futures::future::BoxFuture<'static, Verification> + Send + Sync,
+>;
+
+/// Verification that is fed the data while it is downloaded
+pub trait Incremental: Send {
+    /// Process the next `bytes` of the download.
+    fn update(&mut self, bytes: &[u8]);
+
+    /// Check all the data passed to `update`.
+    fn finish(self: Box<Self>) -> Verification;
+}
+
+/// A factory for `Incremental` verification, called whenever a download
+/// (re)starts from the beginning.
+pub type Streaming = std::sync::Arc<dyn Fn() -> Box<dyn Incremental> + Send + Sync>;
+
+/// Why a verification failed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Failure {
+    /// The data does not match the expected hash.
+    HashMismatch {
+        /// The name of the hash algorithm, e.g. `sha256`, if known
+        algorithm: Option<String>,
+        /// The expected hash
+        expected: Vec<u8>,
+        /// The hash of the data
+        actual: Vec<u8>,
+    },
+    /// The data does not have the expected size.
+    SizeMismatch {
+        /// The expected size in bytes
+        expected: u64,
+        /// The size of the data in bytes
+        actual: u64,
+    },
+    /// The signature does not match the data.
+    SignatureInvalid(String),
+    /// The data could not be read.
+    Io(String),
+    /// Any other reason.
+    Other(String),
+}
+
+impl Failure {
+    /// The data at `path` could not be read
+    pub(crate) fn io(path: &std::path::Path, error: &std::io::Error) -> Self {
+        Self::Io(format!("{}: {error}", path.display()))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HashMismatch {
+                algorithm,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} mismatch: expected {}, got {}",
+                algorithm.as_deref().unwrap_or("hash"),
+                to_hex(expected),
+                to_hex(actual)
+            ),
+            Self::SizeMismatch { expected, actual } => write!(
+                f,
+                "size mismatch: expected {expected} bytes, got {actual} bytes"
+            ),
+            Self::SignatureInvalid(reason) => write!(f, "invalid signature: {reason}"),
+            Self::Io(reason) => write!(f, "failed to read data: {reason}"),
+            Self::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
 /// The possible states of file verification
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Verification {
     /// The file has not been verified at all.
     NotVerified,
     /// The file failed the verification process.
-    Failed,
+    Failed(Box<Failure>),
     /// The file passed the verification process.
     Ok,
 }
 
 impl std::fmt::Display for Verification {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match &self {
-                Self::NotVerified => "not verified",
-                Self::Failed => "FAILED",
-                Self::Ok => "Ok",
-            }
-        )
+        match &self {
+            Self::NotVerified => write!(f, "not verified"),
+            Self::Failed(failure) => write!(f, "FAILED: {failure}"),
+            Self::Ok => write!(f, "Ok"),
+        }
+    }
+}
+
+impl Verification {
+    /// Whether the verification failed
+    #[must_use]
+    pub const fn is_failed(&self) -> bool {
+        matches!(self, Self::Failed(_))
+    }
+
+    /// Combine the results of two verifications of the same data
+    ///
+    /// The first failure is kept.
+    pub(crate) fn and(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Failed(failure), _) | (_, Self::Failed(failure)) => Self::Failed(failure),
+            (Self::Ok, _) | (_, Self::Ok) => Self::Ok,
+            _ => Self::NotVerified,
+        }
+    }
+}
+
+/// Feed the file at `path` into `verifier`
+pub(crate) fn feed_file(
+    path: &std::path::Path,
+    verifier: &mut dyn Incremental,
+    cb: &SimpleProgress,
+) -> std::io::Result<()> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    let mut current = 0;
+    loop {
+        let n = file.read(&mut buffer[..])?;
+        if n == 0 {
+            return Ok(());
+        }
+        verifier.update(&buffer[..n]);
+
+        current += n as u64;
+        cb(current);
+    }
+}
+
+/// Verify the file at `path` with a fresh verifier made by `streaming`
+pub(crate) fn verify_file(
+    streaming: &Streaming,
+    path: &std::path::Path,
+    cb: &SimpleProgress,
+) -> Verification {
+    let mut verifier = streaming();
+    match feed_file(path, verifier.as_mut(), cb) {
+        Ok(()) => verifier.finish(),
+        Err(e) => Verification::Failed(Box::new(Failure::io(path, &e))),
     }
 }
 
@@ -55,49 +195,484 @@ pub fn noop() -> crate::Verify {
 }
 
 // ----------------------------------------------------------------------
-// - SHA3:
+// - Async:
 // ----------------------------------------------------------------------
 
-/// Make sure the downloaded file matches a provided hash using a provided Digest function
+/// Verify the download with the future returned by `func`
+#[must_use]
+pub fn asynchronous<F, Fut>(func: F) -> AsyncVerify
+where
+    F: Fn(std::path::PathBuf) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Verification> + Send + 'static,
+{
+    use futures::FutureExt;
+
+    std::sync::Arc::new(move |path| func(path).boxed())
+}
+
+// ----------------------------------------------------------------------
+// - Size:
+// ----------------------------------------------------------------------
+
+/// Make sure the downloaded file is exactly `size` bytes long
+#[must_use]
+pub fn with_size(size: u64) -> crate::Verify {
+    std::sync::Arc::new(move |path: std::path::PathBuf, _: &crate::SimpleProgress| {
+        match std::fs::metadata(&path) {
+            Ok(m) if m.len() == size => Verification::Ok,
+            Ok(m) => Verification::Failed(Box::new(Failure::SizeMismatch {
+                expected: size,
+                actual: m.len(),
+            })),
+            Err(e) => Verification::Failed(Box::new(Failure::io(&path, &e))),
+        }
+    })
+}
+
+// ----------------------------------------------------------------------
+// - Combinators:
+// ----------------------------------------------------------------------
+
+/// Run the `streaming` verification on the downloaded file
+///
+/// This turns e.g. the checks made by the `checksum` module into something
+/// `all_of` and `any_of` can combine with other checks.
+#[must_use]
+pub fn from_streaming(streaming: Streaming) -> crate::Verify {
+    std::sync::Arc::new(
+        move |path: std::path::PathBuf, cb: &crate::SimpleProgress| {
+            verify_file(&streaming, &path, cb)
+        },
+    )
+}
+
+/// Make sure the downloaded file passes all of the `verifiers`
+///
+/// The `verifiers` run in order and the first failure ends the
+/// verification, so put cheap checks first. That failure is reported. The
+/// result is `Ok` only if all `verifiers` report `Ok` and there is at least
+/// one of them.
+///
+/// Use `from_streaming` to include checks like the ones from the `checksum`
+/// module.
+#[must_use]
+pub fn all_of(verifiers: Vec<crate::Verify>) -> crate::Verify {
+    std::sync::Arc::new(
+        move |path: std::path::PathBuf, cb: &crate::SimpleProgress| {
+            let mut result = if verifiers.is_empty() {
+                Verification::NotVerified
+            } else {
+                Verification::Ok
+            };
+            for verifier in &verifiers {
+                match verifier(path.clone(), cb) {
+                    Verification::Failed(failure) => return Verification::Failed(failure),
+                    Verification::NotVerified => result = Verification::NotVerified,
+                    Verification::Ok => {}
+                }
+            }
+            result
+        },
+    )
+}
+
+/// Make sure the downloaded file passes at least one of the `verifiers`
+///
+/// The `verifiers` run in order and the first success ends the
+/// verification. The result is `Failed` with the last failure if no verifier
+/// reports `Ok` and at least one of them fails.
+#[must_use]
+pub fn any_of(verifiers: Vec<crate::Verify>) -> crate::Verify {
+    std::sync::Arc::new(
+        move |path: std::path::PathBuf, cb: &crate::SimpleProgress| {
+            let mut result = Verification::NotVerified;
+            for verifier in &verifiers {
+                match verifier(path.clone(), cb) {
+                    Verification::Ok => return Verification::Ok,
+                    failed @ Verification::Failed(_) => result = failed,
+                    Verification::NotVerified => {}
+                }
+            }
+            result
+        },
+    )
+}
+
+// ----------------------------------------------------------------------
+// - Digest:
+// ----------------------------------------------------------------------
+
+#[cfg(feature = "verify")]
+fn compare_hash(algorithm: Option<&str>, expected: &[u8], actual: &[u8]) -> Verification {
+    if expected == actual {
+        Verification::Ok
+    } else {
+        Verification::Failed(Box::new(Failure::HashMismatch {
+            algorithm: algorithm.map(str::to_owned),
+            expected: expected.to_vec(),
+            actual: actual.to_vec(),
+        }))
+    }
+}
+
+#[cfg(feature = "verify")]
+struct DigestVerifier<D: digest::Digest> {
+    hasher: D,
+    algorithm: Option<std::sync::Arc<str>>,
+    hash: std::sync::Arc<[u8]>,
+}
+
+#[cfg(feature = "verify")]
+impl<D: digest::Digest + Send> Incremental for DigestVerifier<D> {
+    fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+    }
+
+    fn finish(self: Box<Self>) -> Verification {
+        compare_hash(
+            self.algorithm.as_deref(),
+            &self.hash,
+            &self.hasher.finalize(),
+        )
+    }
+}
+
+#[cfg(feature = "verify")]
+fn named_streaming_digest<D: digest::Digest + Send + 'static>(
+    algorithm: Option<&str>,
+    hash: Vec<u8>,
+) -> Streaming {
+    let algorithm: Option<std::sync::Arc<str>> = algorithm.map(Into::into);
+    let hash: std::sync::Arc<[u8]> = hash.into();
+    std::sync::Arc::new(move || {
+        Box::new(DigestVerifier::<D> {
+            hasher: D::new(),
+            algorithm: algorithm.clone(),
+            hash: hash.clone(),
+        })
+    })
+}
+
+/// Make sure the downloaded data matches a provided hash using a provided
+/// Digest function, hashing the data while it is downloaded
 #[cfg(feature = "verify")]
 #[must_use]
-pub fn with_digest<D: digest::Digest>(hash: Vec<u8>) -> crate::Verify {
+pub fn streaming_digest<D: digest::Digest + Send + 'static>(hash: Vec<u8>) -> Streaming {
+    named_streaming_digest::<D>(None, hash)
+}
+
+/// Like `streaming_digest`, but mention the `algorithm` by name when the
+/// hash does not match
+#[cfg(feature = "verify")]
+#[must_use]
+pub fn streaming_named_digest<D: digest::Digest + Send + 'static>(
+    algorithm: &str,
+    hash: Vec<u8>,
+) -> Streaming {
+    named_streaming_digest::<D>(Some(algorithm), hash)
+}
+
+#[cfg(feature = "verify")]
+fn named_digest<D: digest::Digest>(algorithm: Option<&str>, hash: Vec<u8>) -> crate::Verify {
     use std::io::Read;
 
+    let algorithm = algorithm.map(str::to_owned);
     std::sync::Arc::new(
         move |path: std::path::PathBuf, cb: &crate::SimpleProgress| {
             let mut hasher = D::new();
 
-            if let Ok(file) = std::fs::OpenOptions::new().read(true).open(&path) {
-                let mut reader = std::io::BufReader::with_capacity(1024 * 1024, file);
-                let mut current = 0;
+            let file = match std::fs::OpenOptions::new().read(true).open(&path) {
+                Ok(file) => file,
+                Err(e) => return Verification::Failed(Box::new(Failure::io(&path, &e))),
+            };
+            let mut reader = std::io::BufReader::with_capacity(1024 * 1024, file);
+            let mut current = 0;
 
-                let mut buffer = [0_u8; 1024 * 1024];
-                while let Ok(n) = reader.read(&mut buffer[..]) {
-                    if n == 0 {
-                        break;
-                    }
+            let mut buffer = vec![0_u8; 1024 * 1024];
+            loop {
+                let n = match reader.read(&mut buffer[..]) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => return Verification::Failed(Box::new(Failure::io(&path, &e))),
+                };
 
-                    hasher.update(&buffer[..n]);
+                hasher.update(&buffer[..n]);
 
-                    cb(current);
-                    current += n as u64;
-                }
+                cb(current);
+                current += n as u64;
+            }
 
-                let result = hasher.finalize();
+            compare_hash(algorithm.as_deref(), &hash, &hasher.finalize())
+        },
+    )
+}
 
-                if result.len() != hash.len() {
-                    return Verification::Failed;
-                }
-                for i in 0..result.len() {
-                    if result[i] != hash[i] {
-                        return Verification::Failed;
-                    }
+/// Make sure the downloaded file matches a provided hash using a provided Digest function
+#[cfg(feature = "verify")]
+#[must_use]
+pub fn with_digest<D: digest::Digest>(hash: Vec<u8>) -> crate::Verify {
+    named_digest::<D>(None, hash)
+}
+
+/// Like `with_digest`, but mention the `algorithm` by name when the hash
+/// does not match
+#[cfg(feature = "verify")]
+#[must_use]
+pub fn with_named_digest<D: digest::Digest>(algorithm: &str, hash: Vec<u8>) -> crate::Verify {
+    named_digest::<D>(Some(algorithm), hash)
+}
+
+/// Make sure the downloaded file is `size` bytes long and matches a provided
+/// hash using a provided Digest function
+///
+/// The size is checked first, so files of the wrong size are not hashed.
+#[cfg(feature = "verify")]
+#[must_use]
+pub fn with_size_and_digest<D: digest::Digest + 'static>(
+    size: u64,
+    hash: Vec<u8>,
+) -> crate::Verify {
+    all_of(vec![with_size(size), with_digest::<D>(hash)])
+}
+
+// ----------------------------------------------------------------------
+// - Minisign:
+// ----------------------------------------------------------------------
+
+/// The largest signature file that will be downloaded
+#[cfg(feature = "signature")]
+const MAX_SIGNATURE_SIZE: u64 = 64 * 1024;
+
+#[cfg(feature = "signature")]
+struct MinisignVerifier<'a>(minisign_verify::StreamVerifier<'a>);
+
+#[cfg(feature = "signature")]
+impl Incremental for MinisignVerifier<'_> {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(mut self: Box<Self>) -> Verification {
+        match self.0.finalize() {
+            Ok(()) => Verification::Ok,
+            Err(e) => Verification::Failed(Box::new(Failure::SignatureInvalid(e.to_string()))),
+        }
+    }
+}
+
+/// Make sure the downloaded file was signed by the owner of `public_key`
+///
+/// `public_key` is either the base64 encoded key or the contents of a
+/// `minisign.pub` file, `signature` is the contents of the detached
+/// `.minisig` file. Only pre-hashed signatures are supported, which is what
+/// minisign creates by default.
+///
+/// # Errors
+/// `Error::DownloadDefinition` if the key or signature can not be parsed.
+#[cfg(feature = "signature")]
+pub fn with_minisign(public_key: &str, signature: &str) -> crate::Result<crate::Verify> {
+    let public_key = minisign_verify::PublicKey::decode(public_key)
+        .or_else(|_| minisign_verify::PublicKey::from_base64(public_key.trim()))
+        .map_err(|e| crate::Error::DownloadDefinition(format!("Invalid public key: {e}")))?;
+    let signature = minisign_verify::Signature::decode(signature)
+        .map_err(|e| crate::Error::DownloadDefinition(format!("Invalid signature: {e}")))?;
+
+    Ok(std::sync::Arc::new(
+        move |path: std::path::PathBuf, cb: &crate::SimpleProgress| {
+            let mut verifier = match public_key.verify_stream(&signature) {
+                Ok(verifier) => Box::new(MinisignVerifier(verifier)),
+                Err(e) => {
+                    return Verification::Failed(Box::new(Failure::SignatureInvalid(e.to_string())))
                 }
-                return Verification::Ok;
+            };
+            match feed_file(&path, verifier.as_mut(), cb) {
+                Ok(()) => verifier.finish(),
+                Err(e) => Verification::Failed(Box::new(Failure::io(&path, &e))),
             }
-
-            Verification::Failed
         },
-    )
+    ))
+}
+
+/// Download the detached signature at `signature_url` with `downloader` and
+/// make sure the downloaded file was signed by the owner of `public_key`
+///
+/// See `with_minisign` for details.
+///
+/// # Errors
+/// The error of the download if the signature could not be downloaded,
+/// `Error::DownloadDefinition` if the key or signature can not be parsed.
+#[cfg(feature = "signature")]
+pub fn fetch_minisign(
+    downloader: &mut crate::Downloader,
+    public_key: &str,
+    signature_url: &str,
+) -> crate::Result<crate::Verify> {
+    let signature = downloader.fetch_small_file(signature_url, MAX_SIGNATURE_SIZE)?;
+    with_minisign(public_key, &String::from_utf8_lossy(&signature))
+}
+
+/// Download the detached signature at `signature_url` with `downloader` and
+/// make sure the downloaded file was signed by the owner of `public_key`
+///
+/// See `with_minisign` for details.
+///
+/// # Errors
+/// The error of the download if the signature could not be downloaded,
+/// `Error::DownloadDefinition` if the key or signature can not be parsed.
+#[cfg(feature = "signature")]
+pub async fn async_fetch_minisign(
+    downloader: &mut crate::Downloader,
+    public_key: &str,
+    signature_url: &str,
+) -> crate::Result<crate::Verify> {
+    let signature = downloader
+        .async_fetch_small_file(signature_url, MAX_SIGNATURE_SIZE)
+        .await?;
+    with_minisign(public_key, &String::from_utf8_lossy(&signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn failed(reason: &str) -> Verification {
+        Verification::Failed(Box::new(Failure::Other(reason.to_owned())))
+    }
+
+    /// A verifier reporting `result`, counting how often it ran in `runs`
+    fn fixed(result: Verification, runs: &std::sync::Arc<AtomicUsize>) -> crate::Verify {
+        let runs = runs.clone();
+        std::sync::Arc::new(move |_: std::path::PathBuf, _: &crate::SimpleProgress| {
+            runs.fetch_add(1, Ordering::SeqCst);
+            result.clone()
+        })
+    }
+
+    fn run(verify: &crate::Verify) -> Verification {
+        verify(std::path::PathBuf::from("unused"), &|_| {})
+    }
+
+    /// Accepts data of `size` bytes
+    struct SizeVerifier {
+        size: u64,
+        seen: u64,
+    }
+
+    impl Incremental for SizeVerifier {
+        fn update(&mut self, bytes: &[u8]) {
+            self.seen += bytes.len() as u64;
+        }
+
+        fn finish(self: Box<Self>) -> Verification {
+            if self.seen == self.size {
+                Verification::Ok
+            } else {
+                Verification::Failed(Box::new(Failure::SizeMismatch {
+                    expected: self.size,
+                    actual: self.seen,
+                }))
+            }
+        }
+    }
+
+    fn streaming_size(size: u64) -> Streaming {
+        std::sync::Arc::new(move || Box::new(SizeVerifier { size, seen: 0 }))
+    }
+
+    #[test]
+    fn and_keeps_first_failure() {
+        assert_eq!(failed("a").and(failed("b")), failed("a"));
+        assert_eq!(Verification::Ok.and(failed("b")), failed("b"));
+        assert_eq!(
+            Verification::NotVerified.and(Verification::Ok),
+            Verification::Ok
+        );
+        assert_eq!(
+            Verification::NotVerified.and(Verification::NotVerified),
+            Verification::NotVerified
+        );
+    }
+
+    #[test]
+    fn all_of_empty() {
+        assert_eq!(run(&all_of(Vec::new())), Verification::NotVerified);
+    }
+
+    #[test]
+    fn all_of_needs_all_ok() {
+        let runs = std::sync::Arc::new(AtomicUsize::new(0));
+        let ok = || fixed(Verification::Ok, &runs);
+        assert_eq!(run(&all_of(vec![ok(), ok()])), Verification::Ok);
+        assert_eq!(
+            run(&all_of(vec![ok(), fixed(Verification::NotVerified, &runs)])),
+            Verification::NotVerified
+        );
+    }
+
+    #[test]
+    fn all_of_reports_first_failure() {
+        let runs = std::sync::Arc::new(AtomicUsize::new(0));
+        let verify = all_of(vec![
+            fixed(Verification::Ok, &runs),
+            fixed(failed("first"), &runs),
+            fixed(failed("second"), &runs),
+        ]);
+        assert_eq!(run(&verify), failed("first"));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn any_of_empty() {
+        assert_eq!(run(&any_of(Vec::new())), Verification::NotVerified);
+    }
+
+    #[test]
+    fn any_of_stops_at_first_ok() {
+        let runs = std::sync::Arc::new(AtomicUsize::new(0));
+        let verify = any_of(vec![
+            fixed(failed("first"), &runs),
+            fixed(Verification::Ok, &runs),
+            fixed(failed("second"), &runs),
+        ]);
+        assert_eq!(run(&verify), Verification::Ok);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn any_of_reports_last_failure() {
+        let runs = std::sync::Arc::new(AtomicUsize::new(0));
+        let verify = any_of(vec![
+            fixed(failed("first"), &runs),
+            fixed(failed("second"), &runs),
+            fixed(Verification::NotVerified, &runs),
+        ]);
+        assert_eq!(run(&verify), failed("second"));
+        assert_eq!(
+            run(&any_of(vec![fixed(Verification::NotVerified, &runs)])),
+            Verification::NotVerified
+        );
+    }
+
+    #[test]
+    fn from_streaming_reads_file() {
+        let path =
+            std::env::temp_dir().join(format!("downloader-verify-test-{}", std::process::id()));
+        std::fs::write(&path, [0_u8; 3000]).unwrap();
+
+        let runs = std::sync::Arc::new(AtomicUsize::new(0));
+        let both = all_of(vec![
+            from_streaming(streaming_size(3000)),
+            fixed(Verification::Ok, &runs),
+        ]);
+        assert_eq!(both(path.clone(), &|_| {}), Verification::Ok);
+        assert!(from_streaming(streaming_size(10))(path.clone(), &|_| {}).is_failed());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            from_streaming(streaming_size(3000))(path, &|_| {}),
+            Verification::Failed(failure) if matches!(*failure, Failure::Io(_))
+        ));
+    }
 }